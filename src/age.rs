@@ -0,0 +1,233 @@
+//! A backend which reads secrets from a directory of files encrypted using
+//! [age](https://age-encryption.org/).
+//!
+//! The directory is specified using `CREDENTIALS_AGE_DIR`, and files are
+//! decrypted using the identity file in `AGE_IDENTITY`, or in
+//! `$XDG_CONFIG_HOME/credentials/identity` or
+//! `~/.config/credentials/identity` by default.  `Location::Path` entries
+//! in the `Secretfile` map to `<dir>/<path>.age`, and
+//! `Location::PathWithKey` entries map to `<dir>/<path>/<key>.age`:
+//!
+//! ```text
+//! DB_PASSWORD db_password
+//! API_TOKEN api-credentials:token
+//! ```
+//!
+//! Such files can be created using `age -R recipients.txt -o
+//! db_password.age`.
+
+use ::age::armor::ArmoredReader;
+use ::age::{x25519, Decryptor, IdentityFile, IdentityFileEntry};
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::backend::Backend;
+use crate::directory;
+use crate::errors::*;
+use crate::secretfile::{config_dir, Location, Secretfile};
+
+/// Fetches credentials from age-encrypted files in a directory.
+pub struct Client {
+    dir: directory::Client,
+    identities: Identities,
+}
+
+impl Client {
+    /// Has the user indicated that they want to enable our age backend, by
+    /// setting `CREDENTIALS_AGE_DIR`?
+    pub fn is_enabled() -> bool {
+        env::var_os("CREDENTIALS_AGE_DIR").is_some()
+    }
+
+    /// Construct a new `age::Client` which reads from the directory in
+    /// `CREDENTIALS_AGE_DIR`, using the identity file in `AGE_IDENTITY` or
+    /// `~/.config/credentials/identity`.
+    pub fn from_env() -> Result<Client> {
+        let root = env::var_os("CREDENTIALS_AGE_DIR").ok_or_else(|| {
+            Error::UndefinedEnvironmentVariable {
+                name: "CREDENTIALS_AGE_DIR".to_owned(),
+                source: env::VarError::NotPresent,
+            }
+        })?;
+        let identity = match env::var_os("AGE_IDENTITY") {
+            Some(path) => PathBuf::from(path),
+            None => config_dir().ok_or(Error::NoHomeDirectory)?.join("identity"),
+        };
+        Client::new(root, identity)
+    }
+
+    /// Construct a new `age::Client` which reads from `root`, using the
+    /// identities in the file `identity`.
+    pub fn new<P, I>(root: P, identity: I) -> Result<Client>
+    where
+        P: Into<PathBuf>,
+        I: AsRef<Path>,
+    {
+        Ok(Client {
+            dir: directory::Client::new(root),
+            identities: Identities::from_path(identity.as_ref())?,
+        })
+    }
+
+    /// Read and decrypt the secret at `relative`, a path relative to our
+    /// root, without the `.age` extension.
+    fn read(&self, relative: PathBuf) -> Result<String> {
+        let mut relative = relative.into_os_string();
+        relative.push(".age");
+        let relative = PathBuf::from(relative);
+        let ciphertext = self.dir.read_bytes(&relative)?;
+        let plaintext =
+            self.identities
+                .decrypt(&ciphertext)
+                .map_err(|err| Error::FileRead {
+                    path: relative,
+                    source: Box::new(err),
+                })?;
+        let value = String::from_utf8(plaintext)?;
+        Ok(value.trim_end_matches(&['\r', '\n'][..]).to_owned())
+    }
+
+    fn get_loc(&self, searched_for: &str, loc: Option<&Location>) -> Result<String> {
+        match loc {
+            None => Err(Error::MissingEntry {
+                name: searched_for.to_owned(),
+            }),
+            Some(Location::Path(ref path)) => self.read(PathBuf::from(path)),
+            Some(Location::PathWithKey(ref path, ref key)) => {
+                self.read(Path::new(path).join(key))
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "age"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        self.get_loc(credential, secretfile.var(credential))
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        self.get_loc(path, secretfile.file(path))
+    }
+}
+
+/// A set of age identities, which we can use to decrypt data.
+pub(crate) struct Identities(Vec<x25519::Identity>);
+
+impl Identities {
+    /// Load identities from an identity file, such as one created using
+    /// `age-keygen`.
+    pub(crate) fn from_path(path: &Path) -> Result<Identities> {
+        let mkerr = |err| Error::FileRead {
+            path: path.to_owned(),
+            source: Box::new(err),
+        };
+        let text = fs::read_to_string(path).map_err(|err| mkerr(err.into()))?;
+        Identities::parse(&text).map_err(mkerr)
+    }
+
+    /// Parse identities in the format used by identity files.
+    pub(crate) fn parse(text: &str) -> Result<Identities> {
+        let file = IdentityFile::from_buffer(io::Cursor::new(text))?;
+        let mut identities = vec![];
+        for entry in file.into_identities() {
+            // Other kinds of identities require age plugins.
+            #[allow(irrefutable_let_patterns)]
+            if let IdentityFileEntry::Native(identity) = entry {
+                identities.push(identity);
+            }
+        }
+        if identities.is_empty() {
+            return Err(Error::Decrypt("no age identities found".into()));
+        }
+        Ok(Identities(identities))
+    }
+
+    /// Decrypt `ciphertext`, which may be either binary or ASCII-armored.
+    pub(crate) fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let decryptor = Decryptor::new(ArmoredReader::new(ciphertext))
+            .map_err(|err| Error::Decrypt(err.into()))?;
+        let decryptor = match decryptor {
+            Decryptor::Recipients(decryptor) => decryptor,
+            Decryptor::Passphrase(_) => {
+                return Err(Error::Decrypt(
+                    "passphrase-encrypted age files are not supported".into(),
+                ))
+            }
+        };
+        let mut reader = decryptor
+            .decrypt(self.0.iter().map(|i| i as &dyn ::age::Identity))
+            .map_err(|err| Error::Decrypt(err.into()))?;
+        let mut plaintext = vec![];
+        reader.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+}
+
+#[tokio::test]
+async fn test_age() {
+    use ::age::armor::{ArmoredWriter, Format};
+    use ::age::secrecy::ExposeSecret;
+    use ::age::Encryptor;
+    use std::io::Write;
+    use std::str::FromStr;
+
+    let identity = x25519::Identity::generate();
+    let encrypt = |plaintext: &str, format| {
+        let encryptor =
+            Encryptor::with_recipients(vec![Box::new(identity.to_public())]).unwrap();
+        let mut out = vec![];
+        let armor = ArmoredWriter::wrap_output(&mut out, format).unwrap();
+        let mut writer = encryptor.wrap_output(armor).unwrap();
+        writer.write_all(plaintext.as_bytes()).unwrap();
+        writer.finish().unwrap().finish().unwrap();
+        out
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let secrets = dir.path().join("secrets");
+    fs::create_dir_all(secrets.join("api-credentials")).unwrap();
+    fs::write(
+        secrets.join("db_password.age"),
+        encrypt("secret\n", Format::Binary),
+    )
+    .unwrap();
+    fs::write(
+        secrets.join("api-credentials/token.age"),
+        encrypt("abc", Format::AsciiArmor),
+    )
+    .unwrap();
+    fs::write(secrets.join("plain.age"), "not encrypted").unwrap();
+    let identity_path = dir.path().join("identity");
+    fs::write(&identity_path, identity.to_string().expose_secret()).unwrap();
+
+    let sf = Secretfile::from_str(
+        "\
+DB_PASSWORD db_password
+API_TOKEN api-credentials:token
+PLAIN plain
+ESCAPE ../secrets/db_password
+>token.txt api-credentials:token
+",
+    )
+    .unwrap();
+    let mut client = Client::new(&secrets, &identity_path).unwrap();
+    assert_eq!("secret", client.var(&sf, "DB_PASSWORD").await.unwrap());
+    assert_eq!("abc", client.var(&sf, "API_TOKEN").await.unwrap());
+    assert_eq!("abc", client.file(&sf, "token.txt").await.unwrap());
+    assert!(client.var(&sf, "PLAIN").await.is_err());
+    assert!(client.var(&sf, "ESCAPE").await.is_err());
+    assert!(client.var(&sf, "NOSUCHVAR").await.is_err());
+}
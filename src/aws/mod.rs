@@ -0,0 +1,335 @@
+//! Backends for Amazon Web Services.
+//!
+//! These use the standard AWS environment variables for credentials and
+//! configuration:
+//!
+//! - `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and (optionally)
+//!   `AWS_SESSION_TOKEN`.
+//! - `AWS_REGION` or `AWS_DEFAULT_REGION`.
+//! - `AWS_ENDPOINT_URL`, or a service-specific variable such as
+//!   `AWS_ENDPOINT_URL_SECRETS_MANAGER`, to use a local stand-in such as
+//!   LocalStack or moto.
+//!
+//! Requests are signed using [Signature Version 4][sigv4].
+//!
+//! [sigv4]: https://docs.aws.amazon.com/general/latest/gr/signature-version-4.html
+
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+use crate::errors::*;
+use crate::http::send_json;
+
+#[cfg(feature = "aws-secrets-manager")]
+pub mod secrets_manager;
+#[cfg(feature = "aws-ssm")]
+pub mod ssm;
+
+/// AWS credentials.
+#[derive(Clone)]
+pub(crate) struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    /// Construct a set of credentials.
+    pub(crate) fn new<S: Into<String>>(
+        access_key_id: S,
+        secret_access_key: S,
+        session_token: Option<String>,
+    ) -> Credentials {
+        Credentials {
+            access_key_id: access_key_id.into(),
+            secret_access_key: secret_access_key.into(),
+            session_token,
+        }
+    }
+
+    /// Read credentials from the standard AWS environment variables.
+    pub(crate) fn from_env() -> Result<Credentials> {
+        Ok(Credentials::new(
+            env_var("AWS_ACCESS_KEY_ID")?,
+            env_var("AWS_SECRET_ACCESS_KEY")?,
+            env::var("AWS_SESSION_TOKEN").ok(),
+        ))
+    }
+}
+
+/// Look up a required environment variable.
+fn env_var(name: &str) -> Result<String> {
+    env::var(name).map_err(|err| Error::UndefinedEnvironmentVariable {
+        name: name.to_owned(),
+        source: err,
+    })
+}
+
+/// Look up our default region.
+pub(crate) fn region_from_env() -> Result<String> {
+    env::var("AWS_REGION").or_else(|_| env_var("AWS_DEFAULT_REGION"))
+}
+
+/// Look up the endpoint for a service, using either `endpoint_var`,
+/// `AWS_ENDPOINT_URL` or the standard endpoint for `prefix` in `region`.
+pub(crate) fn endpoint_from_env(
+    endpoint_var: &str,
+    prefix: &str,
+    region: &str,
+) -> Result<Url> {
+    let endpoint = env::var(endpoint_var)
+        .or_else(|_| env::var("AWS_ENDPOINT_URL"))
+        .unwrap_or_else(|_| format!("https://{}.{}.amazonaws.com/", prefix, region));
+    Ok(endpoint.parse()?)
+}
+
+/// A client for AWS services which use the JSON protocol, where each
+/// action is a `POST` with an `X-Amz-Target` header.
+pub(crate) struct JsonClient {
+    client: reqwest::Client,
+    endpoint: Url,
+    credentials: Credentials,
+    region: String,
+    /// The service name used when signing requests.
+    service: &'static str,
+    /// The prefix of our `X-Amz-Target` header.
+    target_prefix: &'static str,
+}
+
+impl JsonClient {
+    /// Construct a new `JsonClient`.
+    pub(crate) fn new(
+        endpoint: Url,
+        credentials: Credentials,
+        region: String,
+        service: &'static str,
+        target_prefix: &'static str,
+    ) -> JsonClient {
+        JsonClient {
+            client: reqwest::Client::new(),
+            endpoint,
+            credentials,
+            region,
+            service,
+            target_prefix,
+        }
+    }
+
+    /// Perform `action`, sending `body` and returning the response.
+    pub(crate) async fn call<B, R>(&self, action: &str, body: &B) -> Result<R>
+    where
+        B: Serialize,
+        R: DeserializeOwned,
+    {
+        let url = self.endpoint.clone();
+        debug!("Calling {} at {}", action, url);
+        let mut req = self
+            .client
+            .post(url.clone())
+            .header("Content-Type", "application/x-amz-json-1.1")
+            .header("X-Amz-Target", format!("{}.{}", self.target_prefix, action))
+            .body(serde_json::to_vec(body)?)
+            .build()
+            .map_err(|err| Error::Url {
+                url: url.clone(),
+                source: Box::new(Error::Other(err.into())),
+            })?;
+        sign(
+            &mut req,
+            &self.credentials,
+            &self.region,
+            self.service,
+            SystemTime::now(),
+        )?;
+        let req = reqwest::RequestBuilder::from_parts(self.client.clone(), req);
+        send_json(&url, req).await
+    }
+}
+
+/// Sign `req` using AWS Signature Version 4, adding `X-Amz-Date`,
+/// `X-Amz-Security-Token` (if needed) and `Authorization` headers.
+pub(crate) fn sign(
+    req: &mut reqwest::Request,
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    now: SystemTime,
+) -> Result<()> {
+    let timestamp = amz_date(now);
+    let date = &timestamp[..8];
+    let header = |value: &str| {
+        HeaderValue::from_str(value).map_err(|err| Error::Other(err.into()))
+    };
+    req.headers_mut().insert("x-amz-date", header(&timestamp)?);
+    if let Some(ref token) = credentials.session_token {
+        req.headers_mut()
+            .insert("x-amz-security-token", header(token)?);
+    }
+
+    // Build our canonical request.
+    let url = req.url();
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_owned(),
+        (None, _) => {
+            return Err(Error::InvalidUrl {
+                url: url.to_string(),
+            })
+        }
+    };
+    let mut headers = vec![("host".to_owned(), host)];
+    for (name, value) in req.headers() {
+        let value = value.to_str().map_err(|err| Error::Other(err.into()))?;
+        headers.push((name.as_str().to_ascii_lowercase(), value.trim().to_owned()));
+    }
+    headers.sort();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let mut query = url
+        .query_pairs()
+        .map(|(k, v)| format!("{}={}", uri_encode(&k), uri_encode(&v)))
+        .collect::<Vec<_>>();
+    query.sort();
+    let payload = req.body().and_then(|b| b.as_bytes()).unwrap_or(&[]);
+    let mut canonical = format!(
+        "{}\n{}\n{}\n",
+        req.method().as_str(),
+        url.path(),
+        query.join("&")
+    );
+    for (name, value) in &headers {
+        canonical.push_str(&format!("{}:{}\n", name, value));
+    }
+    canonical.push_str(&format!(
+        "\n{}\n{}",
+        signed_headers,
+        hex(&Sha256::digest(payload))
+    ));
+
+    // Sign it.
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        timestamp,
+        scope,
+        hex(&Sha256::digest(canonical.as_bytes()))
+    );
+    let key = format!("AWS4{}", credentials.secret_access_key);
+    let key = hmac(key.as_bytes(), date.as_bytes());
+    let key = hmac(&key, region.as_bytes());
+    let key = hmac(&key, service.as_bytes());
+    let key = hmac(&key, b"aws4_request");
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id, scope, signed_headers, signature
+    );
+    req.headers_mut()
+        .insert(AUTHORIZATION, header(&authorization)?);
+    Ok(())
+}
+
+/// Compute an HMAC-SHA256.
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Format `bytes` as lowercase hexadecimal.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Percent-encode everything except unreserved characters, as required by
+/// AWS.
+fn uri_encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Format `time` as `YYYYMMDDTHHMMSSZ`.
+fn amz_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Convert days since the epoch to a civil date, using Howard Hinnant's
+    // `civil_from_days` algorithm.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[test]
+fn test_amz_date() {
+    use std::time::Duration;
+
+    assert_eq!("19700101T000000Z", amz_date(UNIX_EPOCH));
+    let time = UNIX_EPOCH + Duration::from_secs(1_440_938_160);
+    assert_eq!("20150830T123600Z", amz_date(time));
+    let time = UNIX_EPOCH + Duration::from_secs(951_782_400);
+    assert_eq!("20000229T000000Z", amz_date(time));
+}
+
+#[test]
+fn test_sign() {
+    use std::time::Duration;
+
+    // The `get-vanilla` case from the AWS Signature Version 4 test suite.
+    let credentials = Credentials::new(
+        "AKIDEXAMPLE",
+        "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+        None,
+    );
+    let mut req = reqwest::Request::new(
+        reqwest::Method::GET,
+        "https://example.amazonaws.com/".parse().unwrap(),
+    );
+    let now = UNIX_EPOCH + Duration::from_secs(1_440_938_160);
+    sign(&mut req, &credentials, "us-east-1", "service", now).unwrap();
+    assert_eq!(
+        "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+         SignedHeaders=host;x-amz-date, \
+         Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+        req.headers()[AUTHORIZATION]
+    );
+}
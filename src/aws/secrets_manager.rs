@@ -0,0 +1,225 @@
+//! A backend for [AWS Secrets Manager][sm].
+//!
+//! `Location::Path` entries in the `Secretfile` are secret IDs (either a
+//! name or an ARN), and return the whole `SecretString`.
+//! `Location::PathWithKey` entries parse the `SecretString` as a JSON
+//! object, and look up the specified key:
+//!
+//! ```text
+//! API_TOKEN prod/api-token
+//! DB_PASSWORD prod/db:password
+//! ```
+//!
+//! This backend is only enabled when `CREDENTIALS_AWS_SECRETS_MANAGER` is
+//! set, because AWS credentials are often in the environment for other
+//! tools.
+//!
+//! [sm]: https://docs.aws.amazon.com/secretsmanager/
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+
+use super::{endpoint_from_env, region_from_env, Credentials, JsonClient};
+use crate::backend::Backend;
+use crate::errors::*;
+use crate::http::json_key;
+use crate::secretfile::{Location, Secretfile};
+
+/// The body of a `GetSecretValue` request.
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetSecretValueRequest<'a> {
+    secret_id: &'a str,
+}
+
+/// The parts of a `GetSecretValue` response that we care about.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetSecretValueResponse {
+    secret_string: Option<String>,
+    secret_binary: Option<String>,
+}
+
+/// Fetches credentials from AWS Secrets Manager.
+pub struct Client {
+    api: JsonClient,
+    /// Local cache of secret values.
+    secrets: BTreeMap<String, String>,
+}
+
+impl Client {
+    /// Has the user asked us to use AWS Secrets Manager?
+    pub fn is_enabled() -> bool {
+        env::var_os("CREDENTIALS_AWS_SECRETS_MANAGER").is_some()
+    }
+
+    /// Construct a new `secrets_manager::Client` using the standard AWS
+    /// environment variables.
+    pub fn from_env() -> Result<Client> {
+        let region = region_from_env()?;
+        let endpoint = endpoint_from_env(
+            "AWS_ENDPOINT_URL_SECRETS_MANAGER",
+            "secretsmanager",
+            &region,
+        )?;
+        Ok(Client::new(JsonClient::new(
+            endpoint,
+            Credentials::from_env()?,
+            region,
+            "secretsmanager",
+            "secretsmanager",
+        )))
+    }
+
+    fn new(api: JsonClient) -> Client {
+        Client {
+            api,
+            secrets: BTreeMap::new(),
+        }
+    }
+
+    /// Fetch the value of the secret `id`.
+    async fn get_secret(&self, id: &str) -> Result<String> {
+        let res: GetSecretValueResponse = self
+            .api
+            .call("GetSecretValue", &GetSecretValueRequest { secret_id: id })
+            .await?;
+        match (res.secret_string, res.secret_binary) {
+            (Some(value), _) => Ok(value),
+            (None, Some(binary)) => Ok(String::from_utf8(base64::decode(binary)?)?),
+            (None, None) => Err(Error::MissingEntry {
+                name: id.to_owned(),
+            }),
+        }
+    }
+
+    async fn get_loc(
+        &mut self,
+        searched_for: &str,
+        loc: Option<Location>,
+    ) -> Result<String> {
+        let (id, key) = match loc {
+            None => {
+                return Err(Error::MissingEntry {
+                    name: searched_for.to_owned(),
+                })
+            }
+            Some(Location::Path(path)) => (path, None),
+            Some(Location::PathWithKey(path, key)) => (path, Some(key)),
+        };
+
+        // Cache secrets, so that related keys come from the same version.
+        if !self.secrets.contains_key(&id) {
+            let secret = self.get_secret(&id).await?;
+            self.secrets.insert(id.clone(), secret);
+        }
+        let secret = &self.secrets[&id];
+
+        match key {
+            None => Ok(secret.clone()),
+            Some(key) => json_key(&id, secret, &key),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "aws-secrets-manager"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        let loc = secretfile.var(credential).cloned();
+        self.get_loc(credential, loc).await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        let loc = secretfile.file(path).cloned();
+        self.get_loc(path, loc).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::Client;
+    use crate::aws::{Credentials, JsonClient};
+    use crate::backend::Backend;
+    use crate::mock_http::MockServer;
+    use crate::secretfile::Secretfile;
+
+    #[tokio::test]
+    async fn test_secrets_manager() {
+        let server = MockServer::start(|req| {
+            let body: serde_json::Value = serde_json::from_slice(&req.body).unwrap();
+            match body["SecretId"].as_str().unwrap() {
+                "prod/api-token" => (200, r#"{"Name":"prod/api-token","SecretString":"abc"}"#.to_owned()),
+                "prod/db" => (
+                    200,
+                    r#"{"Name":"prod/db","SecretString":"{\"username\":\"app\",\"password\":\"secret\",\"port\":5432}"}"#.to_owned(),
+                ),
+                "prod/binary" => (200, r#"{"Name":"prod/binary","SecretBinary":"Ynl0ZXM="}"#.to_owned()),
+                _ => (
+                    400,
+                    r#"{"__type":"ResourceNotFoundException","message":"Secrets Manager can't find the specified secret."}"#.to_owned(),
+                ),
+            }
+        })
+        .await
+        .unwrap();
+
+        let credentials = Credentials::new("AKID", "SECRET", Some("TOKEN".to_owned()));
+        let api = JsonClient::new(
+            server.url(),
+            credentials,
+            "us-east-1".to_owned(),
+            "secretsmanager",
+            "secretsmanager",
+        );
+        let mut client = Client::new(api);
+
+        let sf = Secretfile::from_str(
+            "\
+API_TOKEN prod/api-token
+DB_USERNAME prod/db:username
+DB_PASSWORD prod/db:password
+DB_PORT prod/db:port
+DB_MISSING prod/db:missing
+BINARY prod/binary
+NOT_FOUND prod/not-found
+",
+        )
+        .unwrap();
+        assert_eq!("abc", client.var(&sf, "API_TOKEN").await.unwrap());
+        assert_eq!("app", client.var(&sf, "DB_USERNAME").await.unwrap());
+        assert_eq!("secret", client.var(&sf, "DB_PASSWORD").await.unwrap());
+        assert_eq!("5432", client.var(&sf, "DB_PORT").await.unwrap());
+        assert!(client.var(&sf, "DB_MISSING").await.is_err());
+        assert_eq!("bytes", client.var(&sf, "BINARY").await.unwrap());
+        let err = client.var(&sf, "NOT_FOUND").await.unwrap_err();
+        assert!(format!("{:?}", err).contains("ResourceNotFoundException"));
+
+        // We only fetch each secret once, and we sign our requests.
+        let requests = server.requests();
+        assert_eq!(4, requests.len());
+        let req = &requests[0];
+        assert_eq!("POST", req.method);
+        assert_eq!(
+            Some("secretsmanager.GetSecretValue"),
+            req.header("x-amz-target")
+        );
+        assert_eq!(Some("TOKEN"), req.header("x-amz-security-token"));
+        let auth = req.header("authorization").unwrap();
+        assert!(auth.starts_with("AWS4-HMAC-SHA256 Credential=AKID/"));
+        assert!(auth.contains("/us-east-1/secretsmanager/aws4_request"));
+        assert!(auth.contains("SignedHeaders=content-type;host;x-amz-date;x-amz-security-token;x-amz-target"));
+    }
+}
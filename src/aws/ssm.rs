@@ -0,0 +1,469 @@
+//! A backend for [AWS Systems Manager Parameter Store][ssm].
+//!
+//! `Location::Path` entries in the `Secretfile` are parameter names, and
+//! `SecureString` parameters are decrypted automatically.
+//! `Location::PathWithKey` entries parse the parameter's value as a JSON
+//! object, and look up the specified key:
+//!
+//! ```text
+//! API_TOKEN /prod/api/token
+//! DB_PASSWORD /prod/db:password
+//! ```
+//!
+//! If `CREDENTIALS_SSM_PREFETCH_PATH` is set, every parameter below that
+//! path is fetched using `GetParametersByPath` the first time we're used,
+//! which is much faster than looking up parameters one at a time.  If this
+//! fails, we fall back to looking up parameters one at a time, and we try
+//! the prefetch again on our next lookup.  We give up on a path after
+//! `MAX_PREFETCH_ATTEMPTS` failures, or as soon as AWS rejects the request
+//! with an error which retrying won't fix, such as `AccessDeniedException`.
+//!
+//! This backend is only enabled when `CREDENTIALS_AWS_SSM` is set, because
+//! AWS credentials are often in the environment for other tools.
+//!
+//! [ssm]: https://docs.aws.amazon.com/systems-manager/latest/userguide/systems-manager-parameter-store.html
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use tracing::{debug, warn};
+
+use super::{endpoint_from_env, region_from_env, Credentials, JsonClient};
+use crate::backend::Backend;
+use crate::errors::*;
+use crate::http::json_key;
+use crate::secretfile::{Location, Secretfile};
+
+/// How many times we try to prefetch a path before giving up on it.
+const MAX_PREFETCH_ATTEMPTS: usize = 3;
+
+/// The body of a `GetParameter` request.
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetParameterRequest<'a> {
+    name: &'a str,
+    with_decryption: bool,
+}
+
+/// The body of a `GetParameter` response.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetParameterResponse {
+    parameter: Parameter,
+}
+
+/// The body of a `GetParametersByPath` request.
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetParametersByPathRequest<'a> {
+    path: &'a str,
+    recursive: bool,
+    with_decryption: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_token: Option<String>,
+}
+
+/// The body of a `GetParametersByPath` response.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetParametersByPathResponse {
+    #[serde(default)]
+    parameters: Vec<Parameter>,
+    next_token: Option<String>,
+}
+
+/// The parts of a parameter that we care about.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Parameter {
+    name: String,
+    value: String,
+}
+
+/// Fetches credentials from AWS SSM Parameter Store.
+pub struct Client {
+    api: JsonClient,
+    /// Paths which we should prefetch before our first lookup, and how many
+    /// times we've failed to fetch each one.
+    prefetch: Vec<(String, usize)>,
+    /// Local cache of parameter values.
+    parameters: BTreeMap<String, String>,
+}
+
+impl Client {
+    /// Has the user asked us to use SSM Parameter Store?
+    pub fn is_enabled() -> bool {
+        env::var_os("CREDENTIALS_AWS_SSM").is_some()
+    }
+
+    /// Construct a new `ssm::Client` using the standard AWS environment
+    /// variables, plus `CREDENTIALS_SSM_PREFETCH_PATH`.
+    pub fn from_env() -> Result<Client> {
+        let region = region_from_env()?;
+        let endpoint = endpoint_from_env("AWS_ENDPOINT_URL_SSM", "ssm", &region)?;
+        let mut client = Client::new(JsonClient::new(
+            endpoint,
+            Credentials::from_env()?,
+            region,
+            "ssm",
+            "AmazonSSM",
+        ));
+        if let Ok(path) = env::var("CREDENTIALS_SSM_PREFETCH_PATH") {
+            client = client.prefetch_path(path);
+        }
+        Ok(client)
+    }
+
+    fn new(api: JsonClient) -> Client {
+        Client {
+            api,
+            prefetch: vec![],
+            parameters: BTreeMap::new(),
+        }
+    }
+
+    /// Fetch all the parameters below `path` using `GetParametersByPath`
+    /// before our first lookup.
+    pub fn prefetch_path<S: Into<String>>(mut self, path: S) -> Client {
+        self.prefetch.push((path.into(), 0));
+        self
+    }
+
+    /// Fetch every parameter below `path`, and add it to our cache.
+    async fn fetch_path(&mut self, path: &str) -> Result<()> {
+        let mut next_token = None;
+        loop {
+            let req = GetParametersByPathRequest {
+                path,
+                recursive: true,
+                with_decryption: true,
+                next_token,
+            };
+            let res: GetParametersByPathResponse =
+                self.api.call("GetParametersByPath", &req).await?;
+            debug!(
+                "Prefetched {} parameters below {}",
+                res.parameters.len(),
+                path
+            );
+            for param in res.parameters {
+                self.parameters.insert(param.name, param.value);
+            }
+            match res.next_token {
+                Some(token) => next_token = Some(token),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Fetch the parameter `name`.
+    async fn get_parameter(&self, name: &str) -> Result<String> {
+        let req = GetParameterRequest {
+            name,
+            with_decryption: true,
+        };
+        let res: GetParameterResponse = self.api.call("GetParameter", &req).await?;
+        Ok(res.parameter.value)
+    }
+
+    async fn get_loc(
+        &mut self,
+        searched_for: &str,
+        loc: Option<Location>,
+    ) -> Result<String> {
+        let (name, key) = match loc {
+            None => {
+                return Err(Error::MissingEntry {
+                    name: searched_for.to_owned(),
+                })
+            }
+            Some(Location::Path(path)) => (path, None),
+            Some(Location::PathWithKey(path, key)) => (path, Some(key)),
+        };
+
+        // Only forget about a prefetch path once we've fetched it, or once
+        // we've given up on it.
+        let mut i = 0;
+        while i < self.prefetch.len() {
+            let path = self.prefetch[i].0.clone();
+            match self.fetch_path(&path).await {
+                Ok(()) => {
+                    self.prefetch.remove(i);
+                }
+                Err(err) => {
+                    self.prefetch[i].1 += 1;
+                    if is_permanent(&err)
+                        || self.prefetch[i].1 >= MAX_PREFETCH_ATTEMPTS
+                    {
+                        warn!(
+                            "Giving up on prefetching parameters below {}: {}",
+                            path, err
+                        );
+                        self.prefetch.remove(i);
+                    } else {
+                        warn!("Could not prefetch parameters below {}: {}", path, err);
+                        i += 1;
+                    }
+                }
+            }
+        }
+        if !self.parameters.contains_key(&name) {
+            let value = self.get_parameter(&name).await?;
+            self.parameters.insert(name.clone(), value);
+        }
+        let value = &self.parameters[&name];
+
+        match key {
+            None => Ok(value.clone()),
+            Some(key) => json_key(&name, value, &key),
+        }
+    }
+}
+
+/// Did an AWS request fail in a way which retrying won't fix?  AWS reports
+/// throttling as a client error, so we treat that as temporary.
+fn is_permanent(err: &Error) -> bool {
+    match err {
+        Error::Url { source, .. } => match **source {
+            Error::UnexpectedHttpStatus { status, ref body } => {
+                status.is_client_error() && !body.contains("ThrottlingException")
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "aws-ssm"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        let loc = secretfile.var(credential).cloned();
+        self.get_loc(credential, loc).await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        let loc = secretfile.file(path).cloned();
+        self.get_loc(path, loc).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use super::Client;
+    use crate::aws::{Credentials, JsonClient};
+    use crate::backend::Backend;
+    use crate::mock_http::MockServer;
+    use crate::secretfile::Secretfile;
+
+    fn parameter(name: &str, value: &str) -> serde_json::Value {
+        serde_json::json!({ "Name": name, "Type": "SecureString", "Value": value })
+    }
+
+    #[tokio::test]
+    async fn test_ssm() {
+        let server = MockServer::start(|req| {
+            let body: serde_json::Value = serde_json::from_slice(&req.body).unwrap();
+            assert_eq!(true, body["WithDecryption"]);
+            let res = match req.header("x-amz-target").unwrap() {
+                "AmazonSSM.GetParametersByPath" => {
+                    assert_eq!("/prod/app", body["Path"]);
+                    if body["NextToken"].is_null() {
+                        serde_json::json!({
+                            "Parameters": [parameter("/prod/app/token", "abc")],
+                            "NextToken": "page2",
+                        })
+                    } else {
+                        serde_json::json!({
+                            "Parameters": [
+                                parameter("/prod/app/db", r#"{"user":"app","password":"secret"}"#),
+                            ],
+                        })
+                    }
+                }
+                "AmazonSSM.GetParameter" => match body["Name"].as_str().unwrap() {
+                    "/prod/other" => {
+                        serde_json::json!({ "Parameter": parameter("/prod/other", "xyz") })
+                    }
+                    _ => {
+                        return (
+                            400,
+                            r#"{"__type":"ParameterNotFound","message":""}"#.to_owned(),
+                        )
+                    }
+                },
+                other => panic!("unexpected target {}", other),
+            };
+            (200, res.to_string())
+        })
+        .await
+        .unwrap();
+
+        let credentials = Credentials::new("AKID", "SECRET", None);
+        let api = JsonClient::new(
+            server.url(),
+            credentials,
+            "eu-west-1".to_owned(),
+            "ssm",
+            "AmazonSSM",
+        );
+        let mut client = Client::new(api).prefetch_path("/prod/app");
+
+        let sf = Secretfile::from_str(
+            "\
+API_TOKEN /prod/app/token
+DB_USER /prod/app/db:user
+DB_PASSWORD /prod/app/db:password
+OTHER /prod/other
+MISSING /prod/missing
+",
+        )
+        .unwrap();
+        assert_eq!("abc", client.var(&sf, "API_TOKEN").await.unwrap());
+        assert_eq!("app", client.var(&sf, "DB_USER").await.unwrap());
+        assert_eq!("secret", client.var(&sf, "DB_PASSWORD").await.unwrap());
+        assert_eq!("xyz", client.var(&sf, "OTHER").await.unwrap());
+        let err = client.var(&sf, "MISSING").await.unwrap_err();
+        assert!(format!("{:?}", err).contains("ParameterNotFound"));
+
+        // Two pages of prefetched parameters, plus two individual lookups.
+        let requests = server.requests();
+        assert_eq!(4, requests.len());
+        let auth = requests[0].header("authorization").unwrap();
+        assert!(auth.contains("/eu-west-1/ssm/aws4_request"));
+    }
+
+    #[tokio::test]
+    async fn test_ssm_prefetch_failure() {
+        // Fail the first prefetch of `/flaky`, and succeed after that.
+        let failed = Arc::new(AtomicBool::new(false));
+        let server = MockServer::start(move |req| {
+            let body: serde_json::Value = serde_json::from_slice(&req.body).unwrap();
+            let res = match req.header("x-amz-target").unwrap() {
+                "AmazonSSM.GetParametersByPath" => match body["Path"].as_str().unwrap() {
+                    "/flaky" if !failed.swap(true, Ordering::SeqCst) => {
+                        return (500, r#"{"__type":"InternalServerError"}"#.to_owned())
+                    }
+                    "/flaky" => serde_json::json!({
+                        "Parameters": [parameter("/flaky/token", "later")],
+                    }),
+                    "/prod/app" => serde_json::json!({
+                        "Parameters": [parameter("/prod/app/token", "abc")],
+                    }),
+                    other => panic!("unexpected path {}", other),
+                },
+                "AmazonSSM.GetParameter" => {
+                    assert_eq!("/flaky/token", body["Name"]);
+                    serde_json::json!({ "Parameter": parameter("/flaky/token", "now") })
+                }
+                other => panic!("unexpected target {}", other),
+            };
+            (200, res.to_string())
+        })
+        .await
+        .unwrap();
+
+        let credentials = Credentials::new("AKID", "SECRET", None);
+        let api = JsonClient::new(
+            server.url(),
+            credentials,
+            "eu-west-1".to_owned(),
+            "ssm",
+            "AmazonSSM",
+        );
+        let mut client = Client::new(api)
+            .prefetch_path("/flaky")
+            .prefetch_path("/prod/app");
+
+        let sf = Secretfile::from_str(
+            "\
+API_TOKEN /prod/app/token
+FLAKY_TOKEN /flaky/token
+",
+        )
+        .unwrap();
+        // `/flaky` fails, but we still prefetch `/prod/app`, and we look up
+        // our parameter directly.
+        assert_eq!("now", client.var(&sf, "FLAKY_TOKEN").await.unwrap());
+        assert_eq!(3, server.requests().len());
+        // Next time, we retry `/flaky`.
+        assert_eq!("abc", client.var(&sf, "API_TOKEN").await.unwrap());
+        assert_eq!(4, server.requests().len());
+        assert_eq!("later", client.var(&sf, "FLAKY_TOKEN").await.unwrap());
+        assert_eq!(4, server.requests().len());
+    }
+
+    #[tokio::test]
+    async fn test_ssm_prefetch_permanent_failure() {
+        let server = MockServer::start(|req| {
+            let body: serde_json::Value = serde_json::from_slice(&req.body).unwrap();
+            let res = match req.header("x-amz-target").unwrap() {
+                "AmazonSSM.GetParametersByPath" => {
+                    match body["Path"].as_str().unwrap() {
+                        "/denied" => {
+                            return (
+                                400,
+                                r#"{"__type":"AccessDeniedException"}"#.to_owned(),
+                            )
+                        }
+                        "/down" => {
+                            return (
+                                500,
+                                r#"{"__type":"InternalServerError"}"#.to_owned(),
+                            )
+                        }
+                        other => panic!("unexpected path {}", other),
+                    }
+                }
+                "AmazonSSM.GetParameter" => {
+                    serde_json::json!({ "Parameter": parameter("/app/token", "abc") })
+                }
+                other => panic!("unexpected target {}", other),
+            };
+            (200, res.to_string())
+        })
+        .await
+        .unwrap();
+
+        let credentials = Credentials::new("AKID", "SECRET", None);
+        let api = JsonClient::new(
+            server.url(),
+            credentials,
+            "eu-west-1".to_owned(),
+            "ssm",
+            "AmazonSSM",
+        );
+        let mut client = Client::new(api)
+            .prefetch_path("/denied")
+            .prefetch_path("/down");
+
+        let sf = Secretfile::from_str("API_TOKEN /app/token\n").unwrap();
+        assert_eq!("abc", client.var(&sf, "API_TOKEN").await.unwrap());
+        assert_eq!(3, server.requests().len());
+        // We don't retry `/denied`, and we retry `/down` until we give up.
+        for _ in 0..3 {
+            assert_eq!("abc", client.var(&sf, "API_TOKEN").await.unwrap());
+        }
+        let prefetches = server
+            .requests()
+            .iter()
+            .filter(|req| {
+                req.header("x-amz-target") == Some("AmazonSSM.GetParametersByPath")
+            })
+            .count();
+        assert_eq!(1 + super::MAX_PREFETCH_ATTEMPTS, prefetches);
+    }
+}
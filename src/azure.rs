@@ -0,0 +1,446 @@
+//! A backend for [Azure Key Vault][akv] secrets.
+//!
+//! `Location::Path` entries in the `Secretfile` name a secret in the vault,
+//! optionally followed by `/<version>`.  `Location::PathWithKey` entries
+//! parse the secret as a JSON object, and look up the specified key:
+//!
+//! ```text
+//! API_TOKEN api-token
+//! DB_PASSWORD db-credentials/0123456789abcdef:password
+//! ```
+//!
+//! The vault is specified using `AZURE_KEYVAULT_URL`.  We authenticate
+//! using a client secret if `AZURE_TENANT_ID`, `AZURE_CLIENT_ID` and
+//! `AZURE_CLIENT_SECRET` are all set, and using a managed identity
+//! otherwise.  `AZURE_AUTHORITY_HOST` and `IDENTITY_ENDPOINT` can be used to
+//! override the URLs used to get tokens.
+//!
+//! [akv]: https://learn.microsoft.com/en-us/azure/key-vault/
+
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+use crate::backend::Backend;
+use crate::errors::*;
+use crate::http::{json_key, send_json, with_trailing_slash};
+use crate::secretfile::{Location, Secretfile};
+
+/// The resource for which we request tokens.
+const RESOURCE: &str = "https://vault.azure.net";
+
+/// The Key Vault API version we use.
+const API_VERSION: &str = "7.4";
+
+/// The default authority for client credentials.
+const DEFAULT_AUTHORITY_HOST: &str = "https://login.microsoftonline.com/";
+
+/// The Azure Instance Metadata Service endpoint for managed identities.
+const IMDS_ENDPOINT: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+
+/// An OAuth2 access token.  Azure AD returns `expires_in`, the Instance
+/// Metadata Service returns both `expires_in` and `expires_on` as strings,
+/// and App Service returns only `expires_on`, the expiry time in seconds
+/// since the Unix epoch.
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<Seconds>,
+    #[serde(default)]
+    expires_on: Option<Seconds>,
+}
+
+impl TokenResponse {
+    /// How long our token is valid for.  If we weren't told, we assume it
+    /// has already expired, so that we don't cache it.
+    fn lifetime(&self) -> Result<Duration> {
+        if let Some(ref expires_in) = self.expires_in {
+            Ok(Duration::from_secs(expires_in.value()?))
+        } else if let Some(ref expires_on) = self.expires_on {
+            let expires_on = UNIX_EPOCH + Duration::from_secs(expires_on.value()?);
+            Ok(expires_on
+                .duration_since(SystemTime::now())
+                .unwrap_or_default())
+        } else {
+            Ok(Duration::default())
+        }
+    }
+}
+
+/// A number of seconds, as either a number or a string.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Seconds {
+    Number(u64),
+    String(String),
+}
+
+impl Seconds {
+    fn value(&self) -> Result<u64> {
+        match *self {
+            Seconds::Number(secs) => Ok(secs),
+            Seconds::String(ref secs) => secs.parse().map_err(|_| Error::Parse {
+                input: secs.to_owned(),
+            }),
+        }
+    }
+}
+
+/// A secret returned by Key Vault.
+#[derive(Deserialize)]
+struct SecretBundle {
+    value: String,
+}
+
+/// How we get access tokens.
+enum Auth {
+    /// Use the OAuth2 client credentials flow.
+    ClientSecret {
+        authority: Url,
+        tenant_id: String,
+        client_id: String,
+        client_secret: String,
+    },
+    /// Use a managed identity endpoint.
+    ManagedIdentity {
+        endpoint: Url,
+        /// The value of `IDENTITY_HEADER`, if we're using App Service-style
+        /// managed identity instead of the Instance Metadata Service.
+        header: Option<String>,
+        /// The client ID of a user-assigned managed identity.
+        client_id: Option<String>,
+    },
+}
+
+/// Fetches credentials from Azure Key Vault.
+pub struct Client {
+    client: reqwest::Client,
+    vault: Url,
+    auth: Auth,
+    /// Our current access token, and when it expires.
+    token: Option<(String, Instant)>,
+    /// Local cache of secrets.
+    secrets: BTreeMap<String, String>,
+}
+
+impl Client {
+    /// Has the user specified a vault using `AZURE_KEYVAULT_URL`?
+    pub fn is_enabled() -> bool {
+        env::var_os("AZURE_KEYVAULT_URL").is_some()
+    }
+
+    /// Construct a new `azure::Client` using the standard Azure environment
+    /// variables.
+    pub fn from_env() -> Result<Client> {
+        let vault = env::var("AZURE_KEYVAULT_URL").map_err(|err| {
+            Error::UndefinedEnvironmentVariable {
+                name: "AZURE_KEYVAULT_URL".to_owned(),
+                source: err,
+            }
+        })?;
+        let client_id = env::var("AZURE_CLIENT_ID").ok();
+        let auth = match (
+            env::var("AZURE_TENANT_ID"),
+            client_id.clone(),
+            env::var("AZURE_CLIENT_SECRET"),
+        ) {
+            (Ok(tenant_id), Some(client_id), Ok(client_secret)) => {
+                let authority = env::var("AZURE_AUTHORITY_HOST")
+                    .unwrap_or_else(|_| DEFAULT_AUTHORITY_HOST.to_owned());
+                Auth::ClientSecret {
+                    authority: with_trailing_slash(&authority).parse()?,
+                    tenant_id,
+                    client_id,
+                    client_secret,
+                }
+            }
+            _ => {
+                let endpoint = env::var("IDENTITY_ENDPOINT")
+                    .unwrap_or_else(|_| IMDS_ENDPOINT.to_owned());
+                Auth::ManagedIdentity {
+                    endpoint: endpoint.parse()?,
+                    header: env::var("IDENTITY_HEADER").ok(),
+                    client_id,
+                }
+            }
+        };
+        Ok(Client::new(with_trailing_slash(&vault).parse()?, auth))
+    }
+
+    fn new(vault: Url, auth: Auth) -> Client {
+        Client {
+            client: reqwest::Client::new(),
+            vault,
+            auth,
+            token: None,
+            secrets: BTreeMap::new(),
+        }
+    }
+
+    /// Get an access token, fetching a new one if ours is missing or about
+    /// to expire.
+    async fn token(&mut self) -> Result<String> {
+        if let Some((ref token, expires)) = self.token {
+            if Instant::now() + Duration::from_secs(60) < expires {
+                return Ok(token.clone());
+            }
+        }
+        let res: TokenResponse = match self.auth {
+            Auth::ClientSecret {
+                ref authority,
+                ref tenant_id,
+                ref client_id,
+                ref client_secret,
+            } => {
+                let url =
+                    authority.join(&format!("{}/oauth2/v2.0/token", tenant_id))?;
+                let scope = format!("{}/.default", RESOURCE);
+                let req = self.client.post(url.clone()).form(&[
+                    ("grant_type", "client_credentials"),
+                    ("client_id", client_id),
+                    ("client_secret", client_secret),
+                    ("scope", &scope),
+                ]);
+                send_json(&url, req).await?
+            }
+            Auth::ManagedIdentity {
+                ref endpoint,
+                ref header,
+                ref client_id,
+            } => {
+                let mut url = endpoint.clone();
+                {
+                    let mut query = url.query_pairs_mut();
+                    let api_version = if header.is_some() {
+                        "2019-08-01"
+                    } else {
+                        "2018-02-01"
+                    };
+                    query.append_pair("api-version", api_version);
+                    query.append_pair("resource", RESOURCE);
+                    if let Some(ref client_id) = *client_id {
+                        query.append_pair("client_id", client_id);
+                    }
+                }
+                let mut req = self.client.get(url.clone()).header("Metadata", "true");
+                if let Some(ref header) = *header {
+                    req = req.header("X-IDENTITY-HEADER", header);
+                }
+                send_json(&url, req).await?
+            }
+        };
+        debug!("Fetched Azure access token");
+        let expires = Instant::now() + res.lifetime()?;
+        self.token = Some((res.access_token.clone(), expires));
+        Ok(res.access_token)
+    }
+
+    /// Fetch the secret `name`, which may include a version.
+    async fn get_secret(&mut self, name: &str) -> Result<String> {
+        let token = self.token().await?;
+        let mut url = self.vault.join(&format!("secrets/{}", name))?;
+        url.query_pairs_mut()
+            .append_pair("api-version", API_VERSION);
+        debug!("Getting secret {}", url);
+        let req = self.client.get(url.clone()).bearer_auth(token);
+        let res: SecretBundle = send_json(&url, req).await?;
+        Ok(res.value)
+    }
+
+    async fn get_loc(
+        &mut self,
+        searched_for: &str,
+        loc: Option<Location>,
+    ) -> Result<String> {
+        let (name, key) = match loc {
+            None => {
+                return Err(Error::MissingEntry {
+                    name: searched_for.to_owned(),
+                })
+            }
+            Some(Location::Path(path)) => (path, None),
+            Some(Location::PathWithKey(path, key)) => (path, Some(key)),
+        };
+
+        if !self.secrets.contains_key(&name) {
+            let secret = self.get_secret(&name).await?;
+            self.secrets.insert(name.clone(), secret);
+        }
+        let secret = &self.secrets[&name];
+
+        match key {
+            None => Ok(secret.clone()),
+            Some(key) => json_key(&name, secret, &key),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "azure-key-vault"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        let loc = secretfile.var(credential).cloned();
+        self.get_loc(credential, loc).await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        let loc = secretfile.file(path).cloned();
+        self.get_loc(path, loc).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::{Auth, Client, TokenResponse};
+    use crate::backend::Backend;
+    use crate::mock_http::{MockServer, Request};
+    use crate::secretfile::Secretfile;
+
+    /// Answer Key Vault requests, as long as we have a valid token.
+    fn key_vault(req: &Request, token: &str) -> (u16, String) {
+        if req.header("authorization") != Some(&format!("Bearer {}", token)[..]) {
+            return (401, r#"{"error":{"code":"Unauthorized"}}"#.to_owned());
+        }
+        let value = match req.path.as_str() {
+            "/vault/secrets/api-token?api-version=7.4" => "abc",
+            "/vault/secrets/db-credentials/v2?api-version=7.4" => {
+                r#"{"user":"app","password":"secret"}"#
+            }
+            _ => return (404, r#"{"error":{"code":"SecretNotFound"}}"#.to_owned()),
+        };
+        (
+            200,
+            serde_json::json!({ "value": value, "id": req.path }).to_string(),
+        )
+    }
+
+    fn secretfile() -> Secretfile {
+        Secretfile::from_str(
+            "\
+API_TOKEN api-token
+DB_USER db-credentials/v2:user
+DB_PASSWORD db-credentials/v2:password
+MISSING missing
+",
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_client_secret() {
+        let server = MockServer::start(|req| {
+            if req.path == "/authority/my-tenant/oauth2/v2.0/token" {
+                let body = String::from_utf8_lossy(&req.body);
+                assert!(body.contains("grant_type=client_credentials"));
+                assert!(body.contains("client_id=my-client"));
+                assert!(body.contains("client_secret=shh"));
+                assert!(body.contains("scope=https%3A%2F%2Fvault.azure.net%2F.default"));
+                return (
+                    200,
+                    r#"{"token_type":"Bearer","expires_in":3599,"access_token":"cc-token"}"#
+                        .to_owned(),
+                );
+            }
+            key_vault(req, "cc-token")
+        })
+        .await
+        .unwrap();
+
+        let auth = Auth::ClientSecret {
+            authority: server.url().join("authority/").unwrap(),
+            tenant_id: "my-tenant".to_owned(),
+            client_id: "my-client".to_owned(),
+            client_secret: "shh".to_owned(),
+        };
+        let mut client = Client::new(server.url().join("vault/").unwrap(), auth);
+        let sf = secretfile();
+        assert_eq!("abc", client.var(&sf, "API_TOKEN").await.unwrap());
+        assert_eq!("app", client.var(&sf, "DB_USER").await.unwrap());
+        assert_eq!("secret", client.var(&sf, "DB_PASSWORD").await.unwrap());
+        let err = client.var(&sf, "MISSING").await.unwrap_err();
+        assert!(format!("{:?}", err).contains("SecretNotFound"));
+
+        // We fetched one token and three secrets.
+        assert_eq!(4, server.requests().len());
+    }
+
+    #[tokio::test]
+    async fn test_managed_identity() {
+        let expires_on = SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+            + Duration::from_secs(86400);
+        let server = MockServer::start(move |req| {
+            if req.path.starts_with("/identity?") {
+                assert_eq!(Some("abc123"), req.header("x-identity-header"));
+                assert!(req.path.contains("api-version=2019-08-01"));
+                assert!(req.path.contains("resource=https%3A%2F%2Fvault.azure.net"));
+                // App Service only tells us when the token expires.
+                let body = serde_json::json!({
+                    "access_token": "mi-token",
+                    "expires_on": expires_on.as_secs().to_string(),
+                    "resource": "https://vault.azure.net",
+                    "token_type": "Bearer",
+                    "client_id": "00000000-0000-0000-0000-000000000000",
+                });
+                return (200, body.to_string());
+            }
+            key_vault(req, "mi-token")
+        })
+        .await
+        .unwrap();
+
+        let auth = Auth::ManagedIdentity {
+            endpoint: server.url().join("identity").unwrap(),
+            header: Some("abc123".to_owned()),
+            client_id: None,
+        };
+        let mut client = Client::new(server.url().join("vault/").unwrap(), auth);
+        let sf = secretfile();
+        assert_eq!("abc", client.var(&sf, "API_TOKEN").await.unwrap());
+        assert_eq!("secret", client.var(&sf, "DB_PASSWORD").await.unwrap());
+
+        // We fetched one token and two secrets.
+        assert_eq!(3, server.requests().len());
+    }
+
+    #[test]
+    fn test_token_lifetime() {
+        let lifetime = |json: &str| {
+            serde_json::from_str::<TokenResponse>(json)
+                .unwrap()
+                .lifetime()
+        };
+        assert_eq!(
+            Duration::from_secs(3599),
+            lifetime(r#"{"access_token":"t","expires_in":3599}"#).unwrap()
+        );
+        assert_eq!(
+            Duration::from_secs(86399),
+            lifetime(r#"{"access_token":"t","expires_in":"86399"}"#).unwrap()
+        );
+        assert_eq!(
+            Duration::default(),
+            lifetime(r#"{"access_token":"t","expires_on":"1586984735"}"#).unwrap()
+        );
+        assert_eq!(
+            Duration::default(),
+            lifetime(r#"{"access_token":"t"}"#).unwrap()
+        );
+        assert!(lifetime(r#"{"access_token":"t","expires_in":"soon"}"#).is_err());
+    }
+}
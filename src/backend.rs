@@ -3,10 +3,45 @@
 use crate::errors::*;
 use crate::secretfile::Secretfile;
 
-/// Generic interface to a secret-storage backend.
+/// Generic interface to a secret-storage backend.  You can implement this
+/// for your own secret store, and pass it to `Options::backends`.
+///
+/// Backends normally use `Secretfile::var` and `Secretfile::file` to find
+/// the `Location` of a credential.  When a backend can't supply a value,
+/// it should return an error (normally `Error::Other`), and the next
+/// backend in the chain will be tried.
+///
+/// ```
+/// use credentials::{async_trait, Backend, Error, Result, Secretfile};
+///
+/// struct MyStore;
+///
+/// #[async_trait]
+/// impl Backend for MyStore {
+///     fn name(&self) -> &'static str {
+///         "my_store"
+///     }
+///
+///     async fn var(
+///         &mut self,
+///         secretfile: &Secretfile,
+///         credential: &str,
+///     ) -> Result<String> {
+///         let location = secretfile.var(credential).ok_or_else(|| {
+///             Error::Other(format!("no entry for {}", credential).into())
+///         })?;
+///         Ok(format!("value of {}", location))
+///     }
+///
+///     async fn file(&mut self, _secretfile: &Secretfile, path: &str) -> Result<String> {
+///         Err(Error::Other(format!("can't fetch {}", path).into()))
+///     }
+/// }
+/// ```
 #[async_trait::async_trait]
 pub trait Backend: Send + Sync {
-    /// Return the name of this backend.
+    /// Return the name of this backend.  This is used for logging, and to
+    /// match the `backend` option in a `Secretfile.toml`.
     fn name(&self) -> &'static str;
 
     /// Get the value of the specified secret.
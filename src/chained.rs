@@ -2,10 +2,39 @@
 
 use tracing::debug;
 
+#[cfg(feature = "age")]
+use crate::age;
+#[cfg(any(feature = "aws-secrets-manager", feature = "aws-ssm"))]
+use crate::aws;
+#[cfg(feature = "azure")]
+use crate::azure;
 use crate::backend::Backend;
+#[cfg(feature = "command")]
+use crate::command;
+#[cfg(feature = "consul")]
+use crate::consul;
+use crate::directory;
+use crate::dotenv;
 use crate::envvar;
 use crate::errors::*;
+#[cfg(feature = "gcp")]
+use crate::gcp;
+#[cfg(feature = "http-json")]
+use crate::http_json;
+#[cfg(feature = "keywhiz")]
+use crate::keywhiz;
+#[cfg(feature = "kubernetes")]
+use crate::kubernetes;
+#[cfg(feature = "onepassword")]
+use crate::onepassword;
+#[cfg(feature = "pass")]
+use crate::pass;
+#[cfg(feature = "secret-service")]
+use crate::secret_service;
 use crate::secretfile::Secretfile;
+#[cfg(feature = "sops")]
+use crate::sops;
+use crate::systemd;
 use crate::vault;
 
 /// Fetches credentials from various other backends, based on which ones
@@ -25,43 +54,142 @@ impl Client {
         self.backends.push(Box::new(backend));
     }
 
+    /// Use the specified backends, in order.
+    pub fn with_backends(backends: Vec<Box<dyn Backend>>) -> Client {
+        let client = Client { backends };
+        client.log_backends();
+        client
+    }
+
     /// Set up the standard chain, based on what appears to be available.
+    /// If any secret stores are enabled, we use them, optionally preceded
+    /// by environment variables if `allow_override` is true.  Otherwise, we
+    /// just use environment variables.  If `allow_override` is true and
+    /// there's a `.env` file, it comes first.
+    ///
+    /// The `kubernetes` store is enabled by `KUBERNETES_SERVICE_HOST`, which
+    /// is set in every pod.  If it can't be set up, we log a warning and
+    /// leave it out, instead of failing.
     pub async fn with_default_backends(allow_override: bool) -> Result<Client> {
-        let mut client = Client::new();
+        let mut stores: Vec<Box<dyn Backend>> = vec![];
+        if systemd::Client::is_enabled() {
+            stores.push(Box::new(systemd::Client::from_env()?));
+        }
+        if directory::Client::is_enabled() {
+            stores.push(Box::new(directory::Client::from_env()?));
+        }
         if vault::Client::is_enabled() {
-            if allow_override {
-                client.add(envvar::Client::default()?);
-            }
-            client.add(vault::Client::default().await?);
-        } else {
-            client.add(envvar::Client::default()?);
+            stores.push(Box::new(vault::Client::default().await?));
+        }
+        #[cfg(feature = "keywhiz")]
+        if keywhiz::Client::is_enabled() {
+            stores.push(Box::new(keywhiz::Client::from_env()?));
+        }
+        #[cfg(feature = "consul")]
+        if consul::Client::is_enabled() {
+            stores.push(Box::new(consul::Client::from_env()?));
+        }
+        #[cfg(feature = "kubernetes")]
+        if kubernetes::Client::is_enabled() {
+            push_detected(&mut stores, "kubernetes", kubernetes::Client::from_env());
+        }
+        #[cfg(feature = "age")]
+        if age::Client::is_enabled() {
+            stores.push(Box::new(age::Client::from_env()?));
+        }
+        #[cfg(feature = "sops")]
+        if sops::Client::is_enabled() {
+            stores.push(Box::new(sops::Client::from_env()?));
+        }
+        #[cfg(feature = "pass")]
+        if pass::Client::is_enabled() {
+            stores.push(Box::new(pass::Client::from_env()?));
+        }
+        #[cfg(feature = "secret-service")]
+        if secret_service::Client::is_enabled() {
+            stores.push(Box::new(secret_service::Client::new()));
+        }
+        #[cfg(feature = "aws-secrets-manager")]
+        if aws::secrets_manager::Client::is_enabled() {
+            stores.push(Box::new(aws::secrets_manager::Client::from_env()?));
+        }
+        #[cfg(feature = "aws-ssm")]
+        if aws::ssm::Client::is_enabled() {
+            stores.push(Box::new(aws::ssm::Client::from_env()?));
+        }
+        #[cfg(feature = "gcp")]
+        if gcp::Client::is_enabled() {
+            stores.push(Box::new(gcp::Client::from_env()?));
+        }
+        #[cfg(feature = "azure")]
+        if azure::Client::is_enabled() {
+            stores.push(Box::new(azure::Client::from_env()?));
+        }
+        #[cfg(feature = "onepassword")]
+        if onepassword::Client::is_enabled() {
+            stores.push(Box::new(onepassword::Client::from_env()?));
+        }
+        #[cfg(feature = "http-json")]
+        if http_json::Client::is_enabled() {
+            stores.push(Box::new(http_json::Client::from_env()?));
+        }
+        #[cfg(feature = "command")]
+        if command::Client::is_enabled() {
+            stores.push(Box::new(command::Client::from_env()?));
         }
 
-        let names: Vec<_> = client.backends.iter().map(|b| b.name()).collect();
-        debug!("Enabled backends: {}", names.join(", "));
+        let mut client = Client::new();
+        if allow_override && dotenv::Client::is_enabled() {
+            client.add(dotenv::Client::from_env()?);
+        }
+        if allow_override || stores.is_empty() {
+            client.add(envvar::Client::default()?);
+        }
+        client.backends.extend(stores);
 
+        client.log_backends();
         Ok(client)
     }
+
+    /// Log the backends we're using, for debugging purposes.
+    fn log_backends(&self) {
+        let names: Vec<_> = self.backends.iter().map(|b| b.name()).collect();
+        debug!("Enabled backends: {}", names.join(", "));
+    }
 }
 
-#[async_trait::async_trait]
-impl Backend for Client {
-    fn name(&self) -> &'static str {
-        "chained"
+/// Add a store which was enabled by ambient configuration to `stores`, or
+/// log a warning and skip it if we couldn't construct it.
+#[cfg(feature = "kubernetes")]
+fn push_detected<B: Backend + 'static>(
+    stores: &mut Vec<Box<dyn Backend>>,
+    name: &str,
+    backend: Result<B>,
+) {
+    match backend {
+        Ok(backend) => stores.push(Box::new(backend)),
+        Err(err) => tracing::warn!("Skipping the {} backend: {}", name, err),
     }
+}
 
-    #[tracing::instrument(level = "debug", skip(self, secretfile))]
-    async fn var(
+impl Client {
+    /// Fetch the value of `credential`, along with the name of the backend
+    /// which supplied it.
+    pub(crate) async fn var_from(
         &mut self,
         secretfile: &Secretfile,
         credential: &str,
-    ) -> Result<String> {
+    ) -> Result<(String, &'static str)> {
         // We want to return either the first success or the last error.
         let mut err: Option<Error> = None;
+        let only = secretfile.var_options(credential).and_then(|o| o.backend());
         for backend in self.backends.iter_mut() {
+            if only.is_some_and(|name| name != backend.name()) {
+                continue;
+            }
             match backend.var(secretfile, credential).await {
                 Ok(value) => {
-                    return Ok(value);
+                    return Ok((value, backend.name()));
                 }
                 Err(e) => {
                     err = Some(e);
@@ -71,14 +199,23 @@ impl Backend for Client {
         Err(err.unwrap_or(Error::NoBackend))
     }
 
-    #[tracing::instrument(level = "debug", skip(self, secretfile))]
-    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+    /// Fetch the contents of the file `path`, along with the name of the
+    /// backend which supplied it.
+    pub(crate) async fn file_from(
+        &mut self,
+        secretfile: &Secretfile,
+        path: &str,
+    ) -> Result<(String, &'static str)> {
         // We want to return either the first success or the last error.
         let mut err: Option<Error> = None;
+        let only = secretfile.file_options(path).and_then(|o| o.backend());
         for backend in self.backends.iter_mut() {
+            if only.is_some_and(|name| name != backend.name()) {
+                continue;
+            }
             match backend.file(secretfile, path).await {
                 Ok(value) => {
-                    return Ok(value);
+                    return Ok((value, backend.name()));
                 }
                 Err(e) => {
                     err = Some(e);
@@ -89,15 +226,49 @@ impl Backend for Client {
     }
 }
 
+/// Does the backend `name` supply values which override our `Secretfile`,
+/// instead of looking them up at a `Secretfile` location?  Transforms don't
+/// apply to these values.
+pub(crate) fn is_override(name: &str) -> bool {
+    name == "env" || name == "dotenv"
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "chained"
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        let (value, _) = self.var_from(secretfile, credential).await?;
+        Ok(value)
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        let (value, _) = self.file_from(secretfile, path).await?;
+        Ok(value)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::env;
     use std::str::FromStr;
 
+    #[cfg(feature = "kubernetes")]
+    use super::push_detected;
     use super::Client;
     use crate::backend::Backend;
     use crate::envvar;
     use crate::errors::*;
+    #[cfg(feature = "kubernetes")]
+    use crate::kubernetes;
     use crate::secretfile::Secretfile;
 
     struct DummyClient;
@@ -154,4 +325,50 @@ mod tests {
         assert_eq!("dummy2", client.file(&sf, "dummy.txt").await.unwrap());
         assert!(client.file(&sf, "nosuchfile.txt").await.is_err());
     }
+
+    #[tokio::test]
+    async fn test_backend_option() {
+        let sf = Secretfile::from_toml(
+            "[vars]\nONLY_DUMMY = { path = \"dummy\", backend = \"dummy\" }\n",
+        )
+        .unwrap();
+        let mut client = Client::new();
+        client.add(envvar::Client::default().unwrap());
+        client.add(DummyClient::default().unwrap());
+
+        // The environment would return a value, but we're only allowed to
+        // ask the dummy backend, which doesn't know about this credential.
+        env::set_var("ONLY_DUMMY", "from env");
+        assert!(client.var(&sf, "ONLY_DUMMY").await.is_err());
+    }
+
+    #[cfg(feature = "kubernetes")]
+    #[test]
+    fn test_skip_broken_kubernetes() {
+        // `KUBERNETES_SERVICE_HOST` is set in every pod, even if there's no
+        // service account for us to use.
+        let dir = tempfile::tempdir().unwrap();
+        let mut stores: Vec<Box<dyn Backend>> = vec![];
+        push_detected(
+            &mut stores,
+            "kubernetes",
+            kubernetes::Client::from_service_account("127.0.0.1", "443", dir.path()),
+        );
+        assert!(stores.is_empty());
+    }
+
+    #[cfg(feature = "kubernetes")]
+    #[test]
+    fn test_push_detected() {
+        let mut stores: Vec<Box<dyn Backend>> = vec![];
+        push_detected(&mut stores, "dummy", DummyClient::default());
+        // Like a pod without a service account token.
+        push_detected::<DummyClient>(
+            &mut stores,
+            "broken",
+            Err(Error::Other("no token".into())),
+        );
+        let names: Vec<_> = stores.iter().map(|b| b.name()).collect();
+        assert_eq!(vec!["dummy"], names);
+    }
 }
@@ -0,0 +1,308 @@
+//! A backend which runs an external command to fetch each secret, in the
+//! style of AWS `credential_process` and git credential helpers.
+//!
+//! The command is specified using `CREDENTIALS_COMMAND`, which is split on
+//! whitespace.  By default, the path and key of each `Secretfile` entry are
+//! passed as extra arguments, so this entry:
+//!
+//! ```text
+//! DB_PASSWORD secret/db:password
+//! ```
+//!
+//! ...will run `$CREDENTIALS_COMMAND secret/db password`.  If
+//! `CREDENTIALS_COMMAND_INPUT` is `json`, the command will instead be sent
+//! a JSON object on standard input:
+//!
+//! ```json
+//! {"name":"DB_PASSWORD","path":"secret/db","key":"password"}
+//! ```
+//!
+//! The secret is read from standard output, minus any trailing newline.
+//! Commands which exit with a non-zero status are treated as errors, and
+//! commands which take longer than `CREDENTIALS_COMMAND_TIMEOUT` seconds
+//! (30 by default) are killed.
+
+use serde::Serialize;
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::time;
+use tracing::debug;
+
+use crate::backend::Backend;
+use crate::errors::*;
+use crate::secretfile::{Location, Secretfile};
+
+/// How should we pass the location of a secret to our command?
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Input {
+    /// Pass the path and key (if any) as extra arguments.
+    #[default]
+    Args,
+    /// Pass the name, path and key as a JSON object on standard input.
+    Json,
+}
+
+/// The JSON object we send to commands using `Input::Json`.
+#[derive(Serialize)]
+struct JsonInput<'a> {
+    name: &'a str,
+    path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<&'a str>,
+}
+
+/// Fetches credentials by running an external command.
+pub struct Client {
+    program: OsString,
+    args: Vec<OsString>,
+    input: Input,
+    timeout: Duration,
+}
+
+impl Client {
+    /// Has the user specified a command using `CREDENTIALS_COMMAND`?
+    pub fn is_enabled() -> bool {
+        env::var_os("CREDENTIALS_COMMAND").is_some()
+    }
+
+    /// Construct a new `command::Client` using `CREDENTIALS_COMMAND`,
+    /// `CREDENTIALS_COMMAND_INPUT` and `CREDENTIALS_COMMAND_TIMEOUT`.
+    pub fn from_env() -> Result<Client> {
+        let command = env::var("CREDENTIALS_COMMAND").map_err(|err| {
+            Error::UndefinedEnvironmentVariable {
+                name: "CREDENTIALS_COMMAND".to_owned(),
+                source: err,
+            }
+        })?;
+        let mut words = command.split_whitespace();
+        let program = words.next().ok_or_else(|| Error::Parse {
+            input: command.clone(),
+        })?;
+        let mut client = Client::new(program).args(words);
+        match env::var("CREDENTIALS_COMMAND_INPUT") {
+            Ok(ref input) if input == "json" => client = client.input(Input::Json),
+            Ok(ref input) if input == "args" => {}
+            Ok(input) => return Err(Error::Parse { input }),
+            Err(_) => {}
+        }
+        if let Ok(timeout) = env::var("CREDENTIALS_COMMAND_TIMEOUT") {
+            let secs = timeout
+                .parse::<f64>()
+                .ok()
+                .filter(|secs| secs.is_finite() && *secs > 0.0)
+                .ok_or(Error::Parse { input: timeout })?;
+            client = client.timeout(Duration::from_secs_f64(secs));
+        }
+        Ok(client)
+    }
+
+    /// Construct a new `command::Client` which runs `program`, passing
+    /// locations as arguments, with a timeout of 30 seconds.
+    pub fn new<P: Into<OsString>>(program: P) -> Client {
+        Client {
+            program: program.into(),
+            args: vec![],
+            input: Input::default(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Pass `args` to our command before any location arguments.
+    pub fn args<I, A>(mut self, args: I) -> Client
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<OsString>,
+    {
+        self.args.extend(args.into_iter().map(|a| a.into()));
+        self
+    }
+
+    /// Specify how we pass locations to our command.
+    pub fn input(mut self, input: Input) -> Client {
+        self.input = input;
+        self
+    }
+
+    /// Kill our command if it runs for longer than `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Client {
+        self.timeout = timeout;
+        self
+    }
+
+    /// A printable version of our command, for error messages.
+    fn command_line(&self) -> String {
+        let mut words = vec![self.program.to_string_lossy()];
+        words.extend(self.args.iter().map(|a| a.to_string_lossy()));
+        words.join(" ")
+    }
+
+    /// Run our command to fetch the secret `name` at `path` and `key`.
+    async fn run(&self, name: &str, path: &str, key: Option<&str>) -> Result<String> {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let stdin = match self.input {
+            Input::Args => {
+                command.arg(path).args(key).stdin(Stdio::null());
+                None
+            }
+            Input::Json => {
+                command.stdin(Stdio::piped());
+                Some(serde_json::to_vec(&JsonInput { name, path, key })?)
+            }
+        };
+
+        let mut child = command.spawn()?;
+        let pipe = child.stdin.take();
+        let run = async {
+            // Write our input while we read the output, in case the command
+            // fills up its output pipe before it reads all of its input.  We
+            // close the pipe once we're done, so the command sees EOF.
+            let write = async move {
+                if let (Some(input), Some(mut pipe)) = (stdin, pipe) {
+                    match pipe.write_all(&input).await {
+                        // The command didn't want the rest of its input.
+                        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+                        result => result?,
+                    }
+                }
+                Ok(())
+            };
+            let (written, output) = tokio::join!(write, child.wait_with_output());
+            written.and(output)
+        };
+        let output = time::timeout(self.timeout, run).await.map_err(|_| {
+            Error::CommandTimedOut {
+                command: self.command_line(),
+                timeout: self.timeout,
+            }
+        })??;
+        if !output.status.success() {
+            return Err(Error::CommandFailed {
+                command: self.command_line(),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            });
+        }
+        debug!("Fetched credential using {}", self.command_line());
+        let value = String::from_utf8(output.stdout)?;
+        Ok(value.trim_end_matches(&['\r', '\n'][..]).to_owned())
+    }
+
+    async fn get_loc(
+        &self,
+        searched_for: &str,
+        loc: Option<&Location>,
+    ) -> Result<String> {
+        match loc {
+            None => Err(Error::MissingEntry {
+                name: searched_for.to_owned(),
+            }),
+            Some(Location::Path(ref path)) => self.run(searched_for, path, None).await,
+            Some(Location::PathWithKey(ref path, ref key)) => {
+                self.run(searched_for, path, Some(key)).await
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "command"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        self.get_loc(credential, secretfile.var(credential)).await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        self.get_loc(path, secretfile.file(path)).await
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_command() {
+    use std::str::FromStr;
+
+    let sf = Secretfile::from_str(
+        "\
+DB_PASSWORD secret/db:password
+API_TOKEN secret/api
+>token.txt secret/api
+",
+    )
+    .unwrap();
+
+    // Locations are passed as arguments by default.
+    let mut client = Client::new("sh").args(["-c", "echo \"$0 $1 $2\"", "prefix"]);
+    assert_eq!(
+        "prefix secret/db password",
+        client.var(&sf, "DB_PASSWORD").await.unwrap()
+    );
+    assert_eq!(
+        "prefix secret/api ",
+        client.file(&sf, "token.txt").await.unwrap()
+    );
+    assert!(client.var(&sf, "NOSUCHVAR").await.is_err());
+
+    // Or as JSON on standard input.
+    let mut client = Client::new("cat").input(Input::Json);
+    assert_eq!(
+        r#"{"name":"DB_PASSWORD","path":"secret/db","key":"password"}"#,
+        client.var(&sf, "DB_PASSWORD").await.unwrap()
+    );
+    assert_eq!(
+        r#"{"name":"API_TOKEN","path":"secret/api"}"#,
+        client.var(&sf, "API_TOKEN").await.unwrap()
+    );
+
+    // Large inputs and outputs don't deadlock, even if the command writes
+    // its output before reading its input.
+    let long = "x".repeat(200_000);
+    let sf_long = Secretfile::from_str(&format!("LONG {}\n", long)).unwrap();
+    let mut client = Client::new("sh")
+        .args([
+            "-c",
+            "head -c 200000 /dev/zero | tr '\\0' y; cat >/dev/null",
+        ])
+        .input(Input::Json)
+        .timeout(Duration::from_secs(5));
+    assert_eq!(200_000, client.var(&sf_long, "LONG").await.unwrap().len());
+
+    // Failures include standard error.
+    let mut client =
+        Client::new("sh").args(["-c", "echo 'no such secret' >&2; exit 3"]);
+    match client.var(&sf, "API_TOKEN").await {
+        Err(Error::CommandFailed { status, stderr, .. }) => {
+            assert_eq!(Some(3), status.code());
+            assert_eq!("no such secret", stderr);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    // Slow commands are killed.
+    let mut client = Client::new("sh")
+        .args(["-c", "sleep 10"])
+        .timeout(Duration::from_millis(100));
+    match client.var(&sf, "API_TOKEN").await {
+        Err(Error::CommandTimedOut { .. }) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
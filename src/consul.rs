@@ -0,0 +1,275 @@
+//! A backend which reads secrets from the [Consul][consul] key/value store.
+//!
+//! `Location::Path` entries in the `Secretfile` are KV keys, and
+//! `Location::PathWithKey` entries parse the value as a JSON object and look
+//! up the specified field:
+//!
+//! ```text
+//! API_TOKEN service/api/token
+//! DB_PASSWORD service/db/credentials:password
+//! ```
+//!
+//! We use the same environment variables as the `consul` CLI:
+//! `CONSUL_HTTP_ADDR`, `CONSUL_HTTP_TOKEN` (or `CONSUL_HTTP_TOKEN_FILE`),
+//! `CONSUL_HTTP_SSL`, `CONSUL_CACERT`, `CONSUL_CLIENT_CERT` and
+//! `CONSUL_CLIENT_KEY`.  The default chain of backends only uses Consul if
+//! `CONSUL_HTTP_ADDR` is set, but `Client::from_env` falls back to the local
+//! agent at `127.0.0.1:8500`, like the `consul` CLI.
+//!
+//! [consul]: https://www.consul.io/
+
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use tracing::debug;
+
+use crate::backend::Backend;
+use crate::errors::*;
+use crate::http::{json_key, send_json};
+use crate::secretfile::{Location, Secretfile};
+
+/// The parts of a KV entry that we care about.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct KvPair {
+    /// The base64-encoded value, if any.
+    value: Option<String>,
+}
+
+/// Fetches credentials from Consul KV.
+pub struct Client {
+    client: reqwest::Client,
+    /// The address of our Consul agent.
+    addr: Url,
+    /// Our ACL token, if any.
+    token: Option<String>,
+    /// Local cache of values.
+    values: BTreeMap<String, String>,
+}
+
+impl Client {
+    /// Has the user specified a Consul agent using `CONSUL_HTTP_ADDR`?
+    pub fn is_enabled() -> bool {
+        env::var_os("CONSUL_HTTP_ADDR").is_some()
+    }
+
+    /// Construct a new `consul::Client` using the standard Consul
+    /// environment variables.  If `CONSUL_HTTP_ADDR` isn't set, we use the
+    /// local agent at `127.0.0.1:8500`.
+    pub fn from_env() -> Result<Client> {
+        let addr = env::var("CONSUL_HTTP_ADDR")
+            .unwrap_or_else(|_| "127.0.0.1:8500".to_owned());
+        let ssl = match env::var("CONSUL_HTTP_SSL") {
+            Ok(ssl) => parse_bool(&ssl)?,
+            Err(_) => false,
+        };
+        let addr = if addr.contains("://") {
+            addr
+        } else if ssl {
+            format!("https://{}", addr)
+        } else {
+            format!("http://{}", addr)
+        };
+
+        let mut builder = reqwest::Client::builder();
+        if env::var_os("CONSUL_CLIENT_CERT").is_some() {
+            let mut identity = read_env_file("CONSUL_CLIENT_KEY")?;
+            identity.extend_from_slice(&read_env_file("CONSUL_CLIENT_CERT")?);
+            let identity = reqwest::Identity::from_pem(&identity)
+                .map_err(|err| Error::Other(err.into()))?;
+            builder = builder.identity(identity);
+        }
+        if env::var_os("CONSUL_CACERT").is_some() {
+            let ca_cert =
+                reqwest::Certificate::from_pem(&read_env_file("CONSUL_CACERT")?)
+                    .map_err(|err| Error::Other(err.into()))?;
+            builder = builder.add_root_certificate(ca_cert);
+        }
+        let client = builder.build().map_err(|err| Error::Other(err.into()))?;
+
+        let token = match env::var("CONSUL_HTTP_TOKEN") {
+            Ok(token) => Some(token),
+            Err(_) if env::var_os("CONSUL_HTTP_TOKEN_FILE").is_some() => {
+                let token = read_env_file("CONSUL_HTTP_TOKEN_FILE")?;
+                Some(String::from_utf8(token)?.trim().to_owned())
+            }
+            Err(_) => None,
+        };
+        Ok(Client::new(client, addr.parse()?, token))
+    }
+
+    fn new(client: reqwest::Client, addr: Url, token: Option<String>) -> Client {
+        Client {
+            client,
+            addr,
+            token,
+            values: BTreeMap::new(),
+        }
+    }
+
+    /// Fetch the value stored at `key`.
+    async fn get_value(&self, key: &str) -> Result<String> {
+        let url = self.addr.join(&format!("v1/kv/{}", key))?;
+        debug!("Getting key {}", url);
+        let mut req = self.client.get(url.clone());
+        if let Some(ref token) = self.token {
+            req = req.header("X-Consul-Token", token);
+        }
+        let pairs: Vec<KvPair> = send_json(&url, req).await?;
+        let value = pairs
+            .into_iter()
+            .next()
+            .and_then(|pair| pair.value)
+            .ok_or_else(|| Error::MissingEntry {
+                name: key.to_owned(),
+            })?;
+        Ok(String::from_utf8(base64::decode(value)?)?)
+    }
+
+    async fn get_loc(
+        &mut self,
+        searched_for: &str,
+        loc: Option<Location>,
+    ) -> Result<String> {
+        let (path, key) = match loc {
+            None => {
+                return Err(Error::MissingEntry {
+                    name: searched_for.to_owned(),
+                })
+            }
+            Some(Location::Path(path)) => (path, None),
+            Some(Location::PathWithKey(path, key)) => (path, Some(key)),
+        };
+
+        if !self.values.contains_key(&path) {
+            let value = self.get_value(&path).await?;
+            self.values.insert(path.clone(), value);
+        }
+        let value = &self.values[&path];
+
+        match key {
+            None => Ok(value.clone()),
+            Some(key) => json_key(&path, value, &key),
+        }
+    }
+}
+
+/// Read the file specified by the environment variable `var`.
+fn read_env_file(var: &str) -> Result<Vec<u8>> {
+    let path = env::var(var).map_err(|err| Error::UndefinedEnvironmentVariable {
+        name: var.to_owned(),
+        source: err,
+    })?;
+    fs::read(&path).map_err(|err| Error::FileRead {
+        path: PathBuf::from(path),
+        source: Box::new(err.into()),
+    })
+}
+
+/// Parse a boolean the way Go's `strconv.ParseBool` does, because that's
+/// what the `consul` CLI uses.
+fn parse_bool(input: &str) -> Result<bool> {
+    match input {
+        "1" | "t" | "T" | "TRUE" | "true" | "True" => Ok(true),
+        "0" | "f" | "F" | "FALSE" | "false" | "False" => Ok(false),
+        _ => Err(Error::Parse {
+            input: input.to_owned(),
+        }),
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "consul"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        let loc = secretfile.var(credential).cloned();
+        self.get_loc(credential, loc).await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        let loc = secretfile.file(path).cloned();
+        self.get_loc(path, loc).await
+    }
+}
+
+#[tokio::test]
+async fn test_consul() {
+    use crate::mock_http::MockServer;
+    use std::str::FromStr;
+
+    let server = MockServer::start(|req| {
+        if req.header("x-consul-token") != Some("acl-token") {
+            return (403, "ACL not found".to_owned());
+        }
+        match req.path.as_str() {
+            "/v1/kv/service/api/token" => (
+                200,
+                r#"[{"Key":"service/api/token","Flags":0,"Value":"YWJj"}]"#.to_owned(),
+            ),
+            "/v1/kv/service/db/credentials" => (
+                200,
+                format!(
+                    r#"[{{"Key":"service/db/credentials","Value":"{}"}}]"#,
+                    base64::encode(r#"{"user":"app","password":"secret"}"#)
+                ),
+            ),
+            "/v1/kv/service/empty" => {
+                (200, r#"[{"Key":"service/empty","Value":null}]"#.to_owned())
+            }
+            _ => (404, "".to_owned()),
+        }
+    })
+    .await
+    .unwrap();
+
+    let mut client = Client::new(
+        reqwest::Client::new(),
+        server.url(),
+        Some("acl-token".to_owned()),
+    );
+    let sf = Secretfile::from_str(
+        "\
+API_TOKEN service/api/token
+DB_USER service/db/credentials:user
+DB_PASSWORD service/db/credentials:password
+DB_MISSING service/db/credentials:missing
+EMPTY service/empty
+MISSING service/missing
+",
+    )
+    .unwrap();
+    assert_eq!("abc", client.var(&sf, "API_TOKEN").await.unwrap());
+    assert_eq!("app", client.var(&sf, "DB_USER").await.unwrap());
+    assert_eq!("secret", client.var(&sf, "DB_PASSWORD").await.unwrap());
+    assert!(client.var(&sf, "DB_MISSING").await.is_err());
+    assert!(client.var(&sf, "EMPTY").await.is_err());
+    assert!(client.var(&sf, "MISSING").await.is_err());
+
+    let mut client = Client::new(reqwest::Client::new(), server.url(), None);
+    let err = client.var(&sf, "API_TOKEN").await.unwrap_err();
+    assert!(format!("{:?}", err).contains("ACL not found"));
+}
+
+#[test]
+fn test_parse_bool() {
+    for input in &["1", "t", "T", "TRUE", "true", "True"] {
+        assert!(parse_bool(input).unwrap());
+    }
+    for input in &["0", "f", "F", "FALSE", "false", "False"] {
+        assert!(!parse_bool(input).unwrap());
+    }
+    assert!(parse_bool("yes").is_err());
+    assert!(parse_bool("").is_err());
+}
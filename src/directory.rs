@@ -0,0 +1,147 @@
+//! A backend which reads secrets mounted as files in a directory.
+//!
+//! This supports Docker Swarm secrets, which are mounted as
+//! `/run/secrets/<name>`, and Kubernetes `Secret` volumes, which contain
+//! one file per key.  `Location::Path` entries in the `Secretfile` map to
+//! `<root>/<path>`, and `Location::PathWithKey` entries map to
+//! `<root>/<path>/<key>`:
+//!
+//! ```text
+//! DB_PASSWORD db_password
+//! API_TOKEN api-credentials:token
+//! ```
+
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use tracing::debug;
+
+use crate::backend::Backend;
+use crate::errors::*;
+use crate::secretfile::{Location, Secretfile};
+
+/// Fetches credentials from files in a directory.
+pub struct Client {
+    root: PathBuf,
+}
+
+impl Client {
+    /// Has the user indicated that they want to enable our directory
+    /// backend, by setting `CREDENTIALS_SECRETS_DIR`?
+    pub fn is_enabled() -> bool {
+        env::var_os("CREDENTIALS_SECRETS_DIR").is_some()
+    }
+
+    /// Construct a new `directory::Client` which reads from the directory
+    /// in `CREDENTIALS_SECRETS_DIR`.
+    pub fn from_env() -> Result<Client> {
+        let root = env::var_os("CREDENTIALS_SECRETS_DIR").ok_or_else(|| {
+            Error::UndefinedEnvironmentVariable {
+                name: "CREDENTIALS_SECRETS_DIR".to_owned(),
+                source: env::VarError::NotPresent,
+            }
+        })?;
+        Ok(Client::new(root))
+    }
+
+    /// Construct a new `directory::Client` which reads from `root`, such as
+    /// `/run/secrets`.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Client {
+        Client { root: root.into() }
+    }
+
+    /// Convert `relative` into a path under our root, rejecting any paths
+    /// which might escape from it.
+    pub(crate) fn resolve(&self, relative: &Path) -> Result<PathBuf> {
+        let safe = relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+        if !safe {
+            return Err(Error::Parse {
+                input: relative.display().to_string(),
+            });
+        }
+        Ok(self.root.join(relative))
+    }
+
+    /// Read the raw contents of `relative`, a path relative to our root.
+    pub(crate) fn read_bytes(&self, relative: &Path) -> Result<Vec<u8>> {
+        let path = self.resolve(relative)?;
+        let value = fs::read(&path).map_err(|err| Error::FileRead {
+            path: path.clone(),
+            source: Box::new(err.into()),
+        })?;
+        debug!("Found credential in {}", path.display());
+        Ok(value)
+    }
+
+    /// Read the secret at `relative`, a path relative to our root.
+    pub(crate) fn read(&self, relative: &Path) -> Result<String> {
+        let value = String::from_utf8(self.read_bytes(relative)?)?;
+        Ok(value.trim_end_matches(&['\r', '\n'][..]).to_owned())
+    }
+
+    /// Read the secret at the specified `Location`.
+    pub(crate) fn get_loc(
+        &self,
+        searched_for: &str,
+        loc: Option<&Location>,
+    ) -> Result<String> {
+        match loc {
+            None => Err(Error::MissingEntry {
+                name: searched_for.to_owned(),
+            }),
+            Some(Location::Path(ref path)) => self.read(Path::new(path)),
+            Some(Location::PathWithKey(ref path, ref key)) => {
+                self.read(&Path::new(path).join(key))
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "directory"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        self.get_loc(credential, secretfile.var(credential))
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        self.get_loc(path, secretfile.file(path))
+    }
+}
+
+#[tokio::test]
+async fn test_directory() {
+    use std::str::FromStr;
+
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("db_password"), "secret\n").unwrap();
+    fs::create_dir(dir.path().join("api-credentials")).unwrap();
+    fs::write(dir.path().join("api-credentials/token"), "abc\r\n").unwrap();
+
+    let sf = Secretfile::from_str(
+        "\
+DB_PASSWORD db_password
+API_TOKEN api-credentials:token
+ESCAPE ../db_password
+>token.txt api-credentials:token
+",
+    )
+    .unwrap();
+    let mut client = Client::new(dir.path());
+    assert_eq!("secret", client.var(&sf, "DB_PASSWORD").await.unwrap());
+    assert_eq!("abc", client.var(&sf, "API_TOKEN").await.unwrap());
+    assert_eq!("abc", client.file(&sf, "token.txt").await.unwrap());
+    assert!(client.var(&sf, "ESCAPE").await.is_err());
+    assert!(client.var(&sf, "NOSUCHVAR").await.is_err());
+}
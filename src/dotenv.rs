@@ -0,0 +1,245 @@
+//! A backend which reads variables from a `.env` file, without modifying
+//! the environment of the current process.
+//!
+//! We support the usual `.env` syntax:
+//!
+//! ```text
+//! # Comments and blank lines are ignored.
+//! DB_USERNAME=admin
+//! export DB_PASSWORD='single-quoted values are literal'
+//! TLS_CERT="double-quoted values may contain \"escapes\",
+//! and may span multiple lines"
+//! API_TOKEN=unquoted # trailing comments are removed
+//! ```
+//!
+//! `$VAR` references are not expanded.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+use crate::backend::Backend;
+use crate::errors::*;
+use crate::secretfile::Secretfile;
+
+/// Fetches credentials from a `.env` file.
+pub struct Client {
+    path: PathBuf,
+    vars: BTreeMap<String, String>,
+}
+
+impl Client {
+    /// Is there a `.env` file in the current directory?
+    pub fn is_enabled() -> bool {
+        Path::new(".env").is_file()
+    }
+
+    /// Construct a new `dotenv::Client` using `.env` in the current
+    /// directory.
+    pub fn from_env() -> Result<Client> {
+        Client::from_path(".env")
+    }
+
+    /// Construct a new `dotenv::Client` using the specified file.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Client> {
+        let path = path.as_ref();
+        let mkerr = |err| Error::FileRead {
+            path: path.to_owned(),
+            source: Box::new(err),
+        };
+        let text = fs::read_to_string(path).map_err(|err| mkerr(err.into()))?;
+        let vars = parse(&text).map_err(mkerr)?;
+        debug!("Read {} variables from {}", vars.len(), path.display());
+        Ok(Client {
+            path: path.to_owned(),
+            vars,
+        })
+    }
+}
+
+/// Parse the contents of a `.env` file.
+fn parse(text: &str) -> Result<BTreeMap<String, String>> {
+    // Only compile this Regex once.
+    lazy_static! {
+        static ref NAME_RE: Regex = Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_.]*$").unwrap();
+    }
+
+    let mut vars = BTreeMap::new();
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        let mkerr = || Error::Parse {
+            input: line.to_owned(),
+        };
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let trimmed = trimmed
+            .strip_prefix("export")
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+            .map(|rest| rest.trim_start())
+            .unwrap_or(trimmed);
+        let (name, value) = trimmed.split_once('=').ok_or_else(mkerr)?;
+        let name = name.trim_end();
+        if !NAME_RE.is_match(name) {
+            return Err(mkerr());
+        }
+
+        let value = value.trim_start();
+        let value = match value.chars().next() {
+            Some(quote @ '\'') | Some(quote @ '"') => {
+                // Keep reading lines until we find the closing quote.
+                let mut raw = value[1..].to_owned();
+                let end = loop {
+                    if let Some(end) = find_closing_quote(&raw, quote) {
+                        break end;
+                    }
+                    let next = lines.next().ok_or_else(mkerr)?;
+                    raw.push('\n');
+                    raw.push_str(next);
+                };
+                let rest = raw[end + 1..].trim_start();
+                if !(rest.is_empty() || rest.starts_with('#')) {
+                    return Err(mkerr());
+                }
+                if quote == '"' {
+                    unescape(&raw[..end])
+                } else {
+                    raw[..end].to_owned()
+                }
+            }
+            _ => {
+                let value = match value.find(" #").or_else(|| value.find("\t#")) {
+                    Some(comment) => &value[..comment],
+                    None if value.starts_with('#') => "",
+                    None => value,
+                };
+                value.trim_end().to_owned()
+            }
+        };
+        vars.insert(name.to_owned(), value);
+    }
+    Ok(vars)
+}
+
+/// Find the byte offset of the `quote` which closes `raw`, skipping
+/// backslash escapes inside double quotes.
+fn find_closing_quote(raw: &str, quote: char) -> Option<usize> {
+    let mut chars = raw.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == quote {
+            return Some(i);
+        } else if c == '\\' && quote == '"' {
+            chars.next();
+        }
+    }
+    None
+}
+
+/// Interpret backslash escapes in a double-quoted value.
+fn unescape(raw: &str) -> String {
+    let mut result = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('t') => result.push('\t'),
+            Some(c @ '"') | Some(c @ '\\') | Some(c @ '$') => result.push(c),
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "dotenv"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, _secretfile))]
+    async fn var(
+        &mut self,
+        _secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        let value = self.vars.get(credential).cloned().ok_or_else(|| {
+            Error::UndefinedDotenvVariable {
+                name: credential.to_owned(),
+                path: self.path.clone(),
+            }
+        })?;
+        debug!("Found credential {} in .env file", credential);
+        Ok(value)
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, _secretfile))]
+    async fn file(&mut self, _secretfile: &Secretfile, path: &str) -> Result<String> {
+        Err(Error::Other(
+            format!("can't read {} from a .env file", path).into(),
+        ))
+    }
+}
+
+#[test]
+fn test_parse() {
+    let data = r#"
+# A comment.
+PLAIN=value
+  SPACED = spaced value   # comment
+export EXPORTED=exported
+EMPTY=
+HASH=abc#def
+SINGLE='literal \n $HOME' # comment
+DOUBLE="line\tone \"quoted\" \$HOME"
+MULTI="first
+second"
+MULTI_SINGLE='a
+b'
+"#;
+    let vars = parse(data).unwrap();
+    assert_eq!("value", vars["PLAIN"]);
+    assert_eq!("spaced value", vars["SPACED"]);
+    assert_eq!("exported", vars["EXPORTED"]);
+    assert_eq!("", vars["EMPTY"]);
+    assert_eq!("abc#def", vars["HASH"]);
+    assert_eq!("literal \\n $HOME", vars["SINGLE"]);
+    assert_eq!("line\tone \"quoted\" $HOME", vars["DOUBLE"]);
+    assert_eq!("first\nsecond", vars["MULTI"]);
+    assert_eq!("a\nb", vars["MULTI_SINGLE"]);
+    assert_eq!(9, vars.len());
+
+    assert!(parse("NO_EQUALS\n").is_err());
+    assert!(parse("BAD NAME=x\n").is_err());
+    assert!(parse("UNCLOSED=\"abc\n").is_err());
+    assert!(parse("TRAILING=\"abc\" def\n").is_err());
+}
+
+#[tokio::test]
+async fn test_var() {
+    use std::str::FromStr;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(".env");
+    fs::write(&path, "DOTENV_USERNAME=user\n").unwrap();
+
+    let sf = Secretfile::from_str("").unwrap();
+    let mut client = Client::from_path(&path).unwrap();
+    assert_eq!("user", client.var(&sf, "DOTENV_USERNAME").await.unwrap());
+    let err = client.var(&sf, "NOSUCHVAR").await.unwrap_err();
+    assert!(err.to_string().contains(&path.display().to_string()));
+    assert!(std::env::var("DOTENV_USERNAME").is_err());
+}
@@ -34,6 +34,11 @@ pub enum Error {
         source: Box<Error>,
     },
 
+    /// We failed to decode base64 data.
+    #[non_exhaustive]
+    #[error("could not decode base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+
     /// We encountered an invalid URL.
     #[non_exhaustive]
     #[error("invalid URL {url:?}")]
@@ -42,6 +47,33 @@ pub enum Error {
         url: String,
     },
 
+    /// We could not decrypt a secret.
+    #[non_exhaustive]
+    #[error("could not decrypt secret: {0}")]
+    Decrypt(Box<dyn std::error::Error + Send + Sync + 'static>),
+
+    /// An external command failed.
+    #[non_exhaustive]
+    #[error("command {command:?} failed with {status}: {stderr}")]
+    CommandFailed {
+        /// The command we ran.
+        command: String,
+        /// The exit status of the command.
+        status: std::process::ExitStatus,
+        /// Anything the command wrote to standard error.
+        stderr: String,
+    },
+
+    /// An external command took too long to run.
+    #[non_exhaustive]
+    #[error("command {command:?} timed out after {timeout:?}")]
+    CommandTimedOut {
+        /// The command we ran.
+        command: String,
+        /// How long we waited.
+        timeout: std::time::Duration,
+    },
+
     /// An error occurred doing I/O.
     #[non_exhaustive]
     #[error("I/O error: {0}")]
@@ -52,6 +84,14 @@ pub enum Error {
     #[error("could not parse JSON: {0}")]
     Json(#[from] serde_json::Error),
 
+    /// JSON value does not have the specified field.
+    #[non_exhaustive]
+    #[error("JSON value does not have a field '{field}'")]
+    MissingJsonField {
+        /// The name of the missing field.
+        field: String,
+    },
+
     /// Missing entry in Secretfile.
     #[non_exhaustive]
     #[error("no entry for '{name}' in Secretfile")]
@@ -102,6 +142,11 @@ pub enum Error {
         path: PathBuf,
     },
 
+    /// A credential value cannot be represented as Unicode.
+    #[non_exhaustive]
+    #[error("value cannot be represented as Unicode: {0}")]
+    NonUnicodeValue(#[from] std::string::FromUtf8Error),
+
     /// Parsing error.
     #[error("could not parse {input:?}")]
     #[non_exhaustive]
@@ -119,6 +164,27 @@ pub enum Error {
     #[error("can't read Secretfile: {0}")]
     Secretfile(Box<Error>),
 
+    /// Can't find a `Secretfile` in any of the places we looked.
+    #[non_exhaustive]
+    #[error(
+        "can't find Secretfile, tried: {}",
+        tried.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    SecretfileNotFound {
+        /// Every path we tried, in order.
+        tried: Vec<PathBuf>,
+    },
+
+    /// Variable not defined in a `.env` file.
+    #[non_exhaustive]
+    #[error("undefined variable {name:?} in {}", path.display())]
+    UndefinedDotenvVariable {
+        /// The name of the variable.
+        name: String,
+        /// The `.env` file we searched.
+        path: PathBuf,
+    },
+
     /// Undefined environment variable.
     #[non_exhaustive]
     #[error("undefined environment variable {name:?}: {source}")]
@@ -130,6 +196,16 @@ pub enum Error {
         source: env::VarError,
     },
 
+    /// Path has a ':key' component, which the backend doesn't support.
+    #[non_exhaustive]
+    #[error("the path '{path}' has a ':{key}' component, but this backend doesn't support keys")]
+    UnexpectedKeyInPath {
+        /// The path.
+        path: String,
+        /// The unsupported key.
+        key: String,
+    },
+
     /// Unexpected HTTP status.
     #[non_exhaustive]
     #[error("unexpected HTTP status: {status} ({body})")]
@@ -140,10 +216,27 @@ pub enum Error {
         body: String,
     },
 
+    /// We failed to parse TOML data.
+    #[non_exhaustive]
+    #[error("could not parse TOML: {0}")]
+    Toml(Box<dyn std::error::Error + Send + Sync + 'static>),
+
     /// We failed to parse a URL.
     #[error("could not parse URL: {0}")]
     UnparseableUrl(#[from] url::ParseError),
 
+    /// An error occurred talking to the Secret Service over D-Bus.
+    #[cfg(feature = "secret-service")]
+    #[non_exhaustive]
+    #[error("could not access the Secret Service: {0}")]
+    SecretService(#[from] zbus::Error),
+
+    /// We failed to parse YAML data.
+    #[cfg(feature = "yaml")]
+    #[non_exhaustive]
+    #[error("could not parse YAML: {0}")]
+    Yaml(Box<dyn std::error::Error + Send + Sync + 'static>),
+
     /// Could not access URL.
     #[non_exhaustive]
     #[error("could not access URL '{url}': {source}")]
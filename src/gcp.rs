@@ -0,0 +1,443 @@
+//! A backend for [Google Cloud Secret Manager][gsm].
+//!
+//! `Location::Path` entries in the `Secretfile` name a secret, which may be
+//! given as `name`, `name/versions/<version>`, `projects/<project>/secrets/
+//! <name>` or `projects/<project>/secrets/<name>/versions/<version>`.  The
+//! project defaults to `GOOGLE_CLOUD_PROJECT`, and the version defaults to
+//! `latest`.  `Location::PathWithKey` entries parse the secret as a JSON
+//! object, and look up the specified key:
+//!
+//! ```text
+//! API_TOKEN api-token
+//! DB_PASSWORD db/versions/3:password
+//! ```
+//!
+//! This backend is enabled by setting `CREDENTIALS_GCP`.  We authenticate
+//! using the service account key file in
+//! `GOOGLE_APPLICATION_CREDENTIALS` if it's set, and the GCE or GKE
+//! metadata server otherwise.  The API endpoint can be overridden using
+//! `CREDENTIALS_GCP_ENDPOINT`, and the metadata server using
+//! `GCE_METADATA_HOST`.
+//!
+//! [gsm]: https://cloud.google.com/secret-manager/docs
+
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+use crate::backend::Backend;
+use crate::errors::*;
+use crate::http::{json_key, send_json};
+use crate::secretfile::{Location, Secretfile};
+
+/// The OAuth2 scope we request.
+const SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+/// The default Secret Manager API endpoint.
+const DEFAULT_ENDPOINT: &str = "https://secretmanager.googleapis.com/";
+
+/// The default metadata server host.
+const DEFAULT_METADATA_HOST: &str = "metadata.google.internal";
+
+/// A service account key file, as downloaded from the Cloud console.
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    project_id: Option<String>,
+    private_key: String,
+    client_email: String,
+    token_uri: String,
+}
+
+/// The claims we send when requesting a token for a service account.
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+/// An OAuth2 access token.
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// The response to an `:access` request.
+#[derive(Deserialize)]
+struct AccessSecretVersionResponse {
+    payload: Payload,
+}
+
+/// The payload of a secret.
+#[derive(Deserialize)]
+struct Payload {
+    data: String,
+}
+
+/// How we get access tokens.
+enum Auth {
+    /// Sign JWTs using a service account key.
+    ServiceAccount(ServiceAccountKey),
+    /// Ask the metadata server at this URL.
+    Metadata(Url),
+}
+
+/// Fetches credentials from Google Cloud Secret Manager.
+pub struct Client {
+    client: reqwest::Client,
+    endpoint: Url,
+    auth: Auth,
+    /// The default project, if we know it.
+    project: Option<String>,
+    /// Our current access token, and when it expires.
+    token: Option<(String, Instant)>,
+    /// Local cache of secrets.
+    secrets: BTreeMap<String, String>,
+}
+
+impl Client {
+    /// Has the user indicated that they want to enable our Google Cloud
+    /// backend, by setting `CREDENTIALS_GCP`?  The standard Google Cloud
+    /// variables are often set for other tools, so they aren't enough.
+    pub fn is_enabled() -> bool {
+        env::var_os("CREDENTIALS_GCP").is_some()
+    }
+
+    /// Construct a new `gcp::Client` using the standard Google Cloud
+    /// environment variables.
+    pub fn from_env() -> Result<Client> {
+        let endpoint = env::var("CREDENTIALS_GCP_ENDPOINT")
+            .unwrap_or_else(|_| DEFAULT_ENDPOINT.to_owned())
+            .parse()?;
+        let mut client = match env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
+            Some(path) => {
+                Client::from_service_account_file(endpoint, Path::new(&path))?
+            }
+            None => {
+                let host = env::var("GCE_METADATA_HOST")
+                    .unwrap_or_else(|_| DEFAULT_METADATA_HOST.to_owned());
+                let metadata =
+                    format!("http://{}/computeMetadata/v1/", host).parse()?;
+                Client::new(endpoint, Auth::Metadata(metadata), None)
+            }
+        };
+        if let Ok(project) = env::var("GOOGLE_CLOUD_PROJECT") {
+            client.project = Some(project);
+        }
+        Ok(client)
+    }
+
+    /// Construct a client which authenticates using the service account
+    /// key file at `path`.
+    fn from_service_account_file(endpoint: Url, path: &Path) -> Result<Client> {
+        let mkerr = |err| Error::FileRead {
+            path: path.to_owned(),
+            source: Box::new(err),
+        };
+        let text = fs::read_to_string(path).map_err(|err| mkerr(err.into()))?;
+        let key: ServiceAccountKey =
+            serde_json::from_str(&text).map_err(|err| mkerr(err.into()))?;
+        let project = key.project_id.clone();
+        Ok(Client::new(endpoint, Auth::ServiceAccount(key), project))
+    }
+
+    fn new(endpoint: Url, auth: Auth, project: Option<String>) -> Client {
+        Client {
+            client: reqwest::Client::new(),
+            endpoint,
+            auth,
+            project,
+            token: None,
+            secrets: BTreeMap::new(),
+        }
+    }
+
+    /// Get an access token, fetching a new one if ours is missing or about
+    /// to expire.
+    async fn token(&mut self) -> Result<String> {
+        if let Some((ref token, expires)) = self.token {
+            if Instant::now() + Duration::from_secs(60) < expires {
+                return Ok(token.clone());
+            }
+        }
+        let res: TokenResponse = match self.auth {
+            Auth::ServiceAccount(ref key) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                let claims = Claims {
+                    iss: &key.client_email,
+                    scope: SCOPE,
+                    aud: &key.token_uri,
+                    iat: now,
+                    exp: now + 3600,
+                };
+                let signing_key = jsonwebtoken::EncodingKey::from_rsa_pem(
+                    key.private_key.as_bytes(),
+                )
+                .map_err(|err| Error::Other(err.into()))?;
+                let header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256);
+                let assertion = jsonwebtoken::encode(&header, &claims, &signing_key)
+                    .map_err(|err| Error::Other(err.into()))?;
+                let url: Url = key.token_uri.parse()?;
+                let req = self.client.post(url.clone()).form(&[
+                    ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                    ("assertion", &assertion),
+                ]);
+                send_json(&url, req).await?
+            }
+            Auth::Metadata(ref metadata) => {
+                let url = metadata.join("instance/service-accounts/default/token")?;
+                let req = self
+                    .client
+                    .get(url.clone())
+                    .header("Metadata-Flavor", "Google");
+                send_json(&url, req).await?
+            }
+        };
+        debug!("Fetched Google Cloud access token");
+        let expires = Instant::now() + Duration::from_secs(res.expires_in);
+        self.token = Some((res.access_token.clone(), expires));
+        Ok(res.access_token)
+    }
+
+    /// Find our default project, asking the metadata server if necessary.
+    async fn project(&mut self) -> Result<String> {
+        if let Some(ref project) = self.project {
+            return Ok(project.clone());
+        }
+        let metadata = match self.auth {
+            Auth::Metadata(ref metadata) => metadata,
+            Auth::ServiceAccount(_) => {
+                return Err(Error::UndefinedEnvironmentVariable {
+                    name: "GOOGLE_CLOUD_PROJECT".to_owned(),
+                    source: env::VarError::NotPresent,
+                })
+            }
+        };
+        let url = metadata.join("project/project-id")?;
+        let mkerr = |err| Error::Url {
+            url: url.clone(),
+            source: Box::new(err),
+        };
+        let res = self
+            .client
+            .get(url.clone())
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|err| mkerr(Error::Other(err.into())))?;
+        let project = res
+            .text()
+            .await
+            .map_err(|err| mkerr(Error::Other(err.into())))?;
+        self.project = Some(project.clone());
+        Ok(project)
+    }
+
+    /// Expand a secret name into a full resource name for a version.
+    async fn resource_name(&mut self, name: &str) -> Result<String> {
+        let name = if name.starts_with("projects/") {
+            name.to_owned()
+        } else {
+            format!("projects/{}/secrets/{}", self.project().await?, name)
+        };
+        if name.contains("/versions/") {
+            Ok(name)
+        } else {
+            Ok(format!("{}/versions/latest", name))
+        }
+    }
+
+    /// Fetch the secret version `resource`.
+    async fn get_secret(&mut self, resource: &str) -> Result<String> {
+        let token = self.token().await?;
+        let url = self.endpoint.join(&format!("v1/{}:access", resource))?;
+        debug!("Getting secret {}", url);
+        let req = self.client.get(url.clone()).bearer_auth(token);
+        let res: AccessSecretVersionResponse = send_json(&url, req).await?;
+        Ok(String::from_utf8(base64::decode(res.payload.data)?)?)
+    }
+
+    async fn get_loc(
+        &mut self,
+        searched_for: &str,
+        loc: Option<Location>,
+    ) -> Result<String> {
+        let (name, key) = match loc {
+            None => {
+                return Err(Error::MissingEntry {
+                    name: searched_for.to_owned(),
+                })
+            }
+            Some(Location::Path(path)) => (path, None),
+            Some(Location::PathWithKey(path, key)) => (path, Some(key)),
+        };
+
+        let resource = self.resource_name(&name).await?;
+        if !self.secrets.contains_key(&resource) {
+            let secret = self.get_secret(&resource).await?;
+            self.secrets.insert(resource.clone(), secret);
+        }
+        let secret = &self.secrets[&resource];
+
+        match key {
+            None => Ok(secret.clone()),
+            Some(key) => json_key(&name, secret, &key),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "gcp-secret-manager"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        let loc = secretfile.var(credential).cloned();
+        self.get_loc(credential, loc).await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        let loc = secretfile.file(path).cloned();
+        self.get_loc(path, loc).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::{Auth, Client, ServiceAccountKey};
+    use crate::backend::Backend;
+    use crate::mock_http::{MockServer, Request};
+    use crate::secretfile::Secretfile;
+
+    /// Generate a throwaway RSA key in PKCS#8 PEM format, like the
+    /// `private_key` in a service account key file.
+    fn test_private_key() -> String {
+        use rsa::pkcs8::{EncodePrivateKey, LineEnding};
+
+        let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        key.to_pkcs8_pem(LineEnding::LF).unwrap().to_string()
+    }
+
+    /// Answer Secret Manager requests, as long as we have a valid token.
+    fn secret_manager(req: &Request, token: &str) -> Option<(u16, String)> {
+        if !req.path.starts_with("/v1/projects/") {
+            return None;
+        }
+        if req.header("authorization") != Some(&format!("Bearer {}", token)[..]) {
+            return Some((401, r#"{"error":{"code":401}}"#.to_owned()));
+        }
+        let data = match req.path.as_str() {
+            "/v1/projects/my-project/secrets/api-token/versions/latest:access" => {
+                "abc"
+            }
+            "/v1/projects/my-project/secrets/db/versions/3:access" => {
+                r#"{"user":"app","password":"secret"}"#
+            }
+            "/v1/projects/other/secrets/shared/versions/latest:access" => "xyz",
+            _ => return Some((404, r#"{"error":{"code":404}}"#.to_owned())),
+        };
+        let body = serde_json::json!({
+            "name": req.path,
+            "payload": { "data": base64::encode(data) },
+        });
+        Some((200, body.to_string()))
+    }
+
+    fn secretfile() -> Secretfile {
+        Secretfile::from_str(
+            "\
+API_TOKEN api-token
+DB_USER db/versions/3:user
+DB_PASSWORD db/versions/3:password
+SHARED projects/other/secrets/shared
+MISSING missing
+",
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_service_account() {
+        let server = MockServer::start(|req| {
+            if req.path == "/token" {
+                let body = String::from_utf8_lossy(&req.body);
+                assert!(body.contains("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer"));
+                assert!(body.contains("assertion="));
+                return (200, r#"{"access_token":"sa-token","expires_in":3600,"token_type":"Bearer"}"#.to_owned());
+            }
+            secret_manager(req, "sa-token").unwrap()
+        })
+        .await
+        .unwrap();
+
+        let key = ServiceAccountKey {
+            project_id: Some("my-project".to_owned()),
+            private_key: test_private_key(),
+            client_email: "test@my-project.iam.gserviceaccount.com".to_owned(),
+            token_uri: server.url().join("token").unwrap().to_string(),
+        };
+        let mut client = Client::new(
+            server.url(),
+            Auth::ServiceAccount(key),
+            Some("my-project".to_owned()),
+        );
+        let sf = secretfile();
+        assert_eq!("abc", client.var(&sf, "API_TOKEN").await.unwrap());
+        assert_eq!("app", client.var(&sf, "DB_USER").await.unwrap());
+        assert_eq!("secret", client.var(&sf, "DB_PASSWORD").await.unwrap());
+        assert_eq!("xyz", client.var(&sf, "SHARED").await.unwrap());
+        assert!(client.var(&sf, "MISSING").await.is_err());
+
+        // We fetched one token and four secrets.
+        let requests = server.requests();
+        assert_eq!(5, requests.len());
+        assert_eq!(1, requests.iter().filter(|r| r.path == "/token").count());
+    }
+
+    #[tokio::test]
+    async fn test_metadata_server() {
+        let server = MockServer::start(|req| {
+            if req.path.starts_with("/computeMetadata/") {
+                assert_eq!(Some("Google"), req.header("metadata-flavor"));
+            }
+            match req.path.as_str() {
+                "/computeMetadata/v1/instance/service-accounts/default/token" => (
+                    200,
+                    r#"{"access_token":"gce-token","expires_in":3600,"token_type":"Bearer"}"#
+                        .to_owned(),
+                ),
+                "/computeMetadata/v1/project/project-id" => (200, "my-project".to_owned()),
+                _ => secret_manager(req, "gce-token").unwrap_or((404, "{}".to_owned())),
+            }
+        })
+        .await
+        .unwrap();
+
+        let metadata = server.url().join("computeMetadata/v1/").unwrap();
+        let mut client = Client::new(server.url(), Auth::Metadata(metadata), None);
+        let sf = secretfile();
+        assert_eq!("abc", client.var(&sf, "API_TOKEN").await.unwrap());
+        assert_eq!("secret", client.var(&sf, "DB_PASSWORD").await.unwrap());
+    }
+}
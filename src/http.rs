@@ -0,0 +1,103 @@
+//! Helpers for backends which talk to HTTP APIs.
+
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+
+use crate::errors::*;
+
+/// Send `req` to `url`, and parse the response as JSON.  Errors, including
+/// unexpected HTTP statuses, are reported along with `url`.
+pub(crate) async fn send_json<T>(url: &Url, req: reqwest::RequestBuilder) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    let mkerr = |err| Error::Url {
+        url: url.clone(),
+        source: Box::new(err),
+    };
+    let res = req
+        .send()
+        .await
+        .map_err(|err| mkerr(Error::Other(err.into())))?;
+
+    if res.status().is_success() {
+        Ok(res
+            .json()
+            .await
+            .map_err(|err| mkerr(Error::Other(err.into())))?)
+    } else {
+        // Generate informative errors for HTTP failures, because these can
+        // be caused by everything from bad URLs to overly restrictive access
+        // policies.
+        let status = res.status().to_owned();
+        let body = res
+            .text()
+            .await
+            .map_err(|err| mkerr(Error::Other(err.into())))?;
+
+        Err(mkerr(Error::UnexpectedHttpStatus {
+            status,
+            body: body.trim().to_owned(),
+        }))
+    }
+}
+
+/// Parse `value` as a JSON object, and look up `key`.  Strings are returned
+/// as is, and other values are returned as JSON.  If `value` isn't valid
+/// JSON, we return `Error::Json`.
+#[cfg(any(
+    feature = "aws-secrets-manager",
+    feature = "aws-ssm",
+    feature = "azure",
+    feature = "consul",
+    feature = "gcp"
+))]
+pub(crate) fn json_key(name: &str, value: &str, key: &str) -> Result<String> {
+    let missing = || Error::MissingKeyInSecret {
+        secret: name.to_owned(),
+        key: key.to_owned(),
+    };
+    let data: serde_json::Value = serde_json::from_str(value)?;
+    match data.get(key) {
+        Some(serde_json::Value::String(value)) => Ok(value.to_owned()),
+        Some(serde_json::Value::Null) | None => Err(missing()),
+        Some(value) => Ok(value.to_string()),
+    }
+}
+
+/// Make sure `url` ends with a `/`, so that we can join paths onto it
+/// without losing its last path segment.
+#[cfg(any(feature = "azure", feature = "keywhiz"))]
+pub(crate) fn with_trailing_slash(url: &str) -> String {
+    if url.ends_with('/') {
+        url.to_owned()
+    } else {
+        format!("{}/", url)
+    }
+}
+
+#[cfg(any(
+    feature = "aws-secrets-manager",
+    feature = "aws-ssm",
+    feature = "azure",
+    feature = "consul",
+    feature = "gcp"
+))]
+#[test]
+fn test_json_key() {
+    let value = r#"{"user":"app","port":5432,"none":null}"#;
+    assert_eq!("app", json_key("db", value, "user").unwrap());
+    assert_eq!("5432", json_key("db", value, "port").unwrap());
+    assert!(matches!(
+        json_key("db", value, "none"),
+        Err(Error::MissingKeyInSecret { .. })
+    ));
+    assert!(matches!(
+        json_key("db", value, "missing"),
+        Err(Error::MissingKeyInSecret { .. })
+    ));
+    assert!(matches!(
+        json_key("db", "not json", "user"),
+        Err(Error::Json(_))
+    ));
+}
@@ -0,0 +1,274 @@
+//! A backend which fetches secrets from a simple HTTP service returning
+//! JSON.
+//!
+//! The service is specified using `CREDENTIALS_HTTP_URL`, a URL template in
+//! which `{path}` is replaced by the path of each `Secretfile` entry.  If
+//! `CREDENTIALS_HTTP_TOKEN` is set, it will be sent as a bearer token, or in
+//! the header named by `CREDENTIALS_HTTP_TOKEN_HEADER`.
+//!
+//! For `Location::PathWithKey` entries, we extract a value from the
+//! response using the [JSON pointer][pointer] in `CREDENTIALS_HTTP_POINTER`
+//! (`/{key}` by default), with `{key}` replaced by the key.  So given:
+//!
+//! ```text
+//! CREDENTIALS_HTTP_URL=https://secrets.example.com/v1/{path}
+//! CREDENTIALS_HTTP_POINTER=/data/{key}
+//! ```
+//!
+//! ...the entry `DB_PASSWORD db:password` will fetch
+//! `https://secrets.example.com/v1/db` and return `data.password`.
+//! `Location::Path` entries return the whole response.  Strings are
+//! returned as is, and other values are returned as JSON.
+//!
+//! [pointer]: https://datatracker.ietf.org/doc/html/rfc6901
+
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::Url;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::env;
+use tracing::debug;
+
+use crate::backend::Backend;
+use crate::errors::*;
+use crate::http::send_json;
+use crate::secretfile::{Location, Secretfile};
+
+/// Fetches credentials from an HTTP service which returns JSON.
+pub struct Client {
+    client: reqwest::Client,
+    url_template: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    pointer_template: String,
+    /// Local cache of responses, indexed by path.
+    responses: BTreeMap<String, Value>,
+}
+
+impl Client {
+    /// Has the user specified a service using `CREDENTIALS_HTTP_URL`?
+    pub fn is_enabled() -> bool {
+        env::var_os("CREDENTIALS_HTTP_URL").is_some()
+    }
+
+    /// Construct a new `http_json::Client` using `CREDENTIALS_HTTP_URL`,
+    /// `CREDENTIALS_HTTP_TOKEN`, `CREDENTIALS_HTTP_TOKEN_HEADER` and
+    /// `CREDENTIALS_HTTP_POINTER`.
+    pub fn from_env() -> Result<Client> {
+        let url = env::var("CREDENTIALS_HTTP_URL").map_err(|err| {
+            Error::UndefinedEnvironmentVariable {
+                name: "CREDENTIALS_HTTP_URL".to_owned(),
+                source: err,
+            }
+        })?;
+        let mut client = Client::new(url);
+        if let Ok(token) = env::var("CREDENTIALS_HTTP_TOKEN") {
+            client = match env::var("CREDENTIALS_HTTP_TOKEN_HEADER") {
+                Ok(header) => client.header(&header, &token)?,
+                Err(_) => {
+                    client.header("Authorization", &format!("Bearer {}", token))?
+                }
+            };
+        }
+        if let Ok(pointer) = env::var("CREDENTIALS_HTTP_POINTER") {
+            client = client.pointer(pointer);
+        }
+        Ok(client)
+    }
+
+    /// Construct a new `http_json::Client` which fetches secrets from
+    /// `url_template`, replacing `{path}` with the path of each secret.
+    pub fn new<S: Into<String>>(url_template: S) -> Client {
+        Client {
+            client: reqwest::Client::new(),
+            url_template: url_template.into(),
+            headers: vec![],
+            pointer_template: "/{key}".to_owned(),
+            responses: BTreeMap::new(),
+        }
+    }
+
+    /// Send the header `name: value` with each request.
+    pub fn header(mut self, name: &str, value: &str) -> Result<Client> {
+        let name = HeaderName::try_from(name).map_err(|_| Error::Parse {
+            input: name.to_owned(),
+        })?;
+        let value = HeaderValue::try_from(value).map_err(|_| Error::Parse {
+            input: name.to_string(),
+        })?;
+        self.headers.push((name, value));
+        Ok(self)
+    }
+
+    /// Extract keys using the JSON pointer `pointer_template`, replacing
+    /// `{key}` with the key of each secret.
+    pub fn pointer<S: Into<String>>(mut self, pointer_template: S) -> Client {
+        self.pointer_template = pointer_template.into();
+        self
+    }
+
+    /// Fetch the response for `path`.
+    async fn fetch(&self, path: &str) -> Result<Value> {
+        let url: Url = self
+            .url_template
+            .replace("{path}", &encode_path(path))
+            .parse()?;
+        debug!("Getting {}", url);
+        let mut req = self.client.get(url.clone());
+        for (name, value) in &self.headers {
+            req = req.header(name, value);
+        }
+        send_json(&url, req).await
+    }
+
+    async fn get_loc(
+        &mut self,
+        searched_for: &str,
+        loc: Option<Location>,
+    ) -> Result<String> {
+        let (path, key) = match loc {
+            None => {
+                return Err(Error::MissingEntry {
+                    name: searched_for.to_owned(),
+                })
+            }
+            Some(Location::Path(path)) => (path, None),
+            Some(Location::PathWithKey(path, key)) => (path, Some(key)),
+        };
+
+        if !self.responses.contains_key(&path) {
+            let response = self.fetch(&path).await?;
+            self.responses.insert(path.clone(), response);
+        }
+        let response = &self.responses[&path];
+
+        let value = match key {
+            None => Some(response),
+            Some(ref key) => {
+                // Escape the key as described in RFC 6901.
+                let key = key.replace('~', "~0").replace('/', "~1");
+                response.pointer(&self.pointer_template.replace("{key}", &key))
+            }
+        };
+        match value {
+            Some(Value::String(value)) => Ok(value.to_owned()),
+            Some(Value::Null) | None => Err(Error::MissingKeyInSecret {
+                secret: path,
+                key: key.unwrap_or_default(),
+            }),
+            Some(value) => Ok(value.to_string()),
+        }
+    }
+}
+
+/// Percent-encode `path` for use in a URL, leaving `/` separators intact.
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~'
+            | b'/' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "http-json"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        let loc = secretfile.var(credential).cloned();
+        self.get_loc(credential, loc).await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        let loc = secretfile.file(path).cloned();
+        self.get_loc(path, loc).await
+    }
+}
+
+#[tokio::test]
+async fn test_http_json() {
+    use crate::mock_http::MockServer;
+    use std::str::FromStr;
+
+    let server = MockServer::start(|req| {
+        if req.header("x-api-key") != Some("key123") {
+            return (401, "unauthorized".to_owned());
+        }
+        let body = match req.path.as_str() {
+            "/v1/secrets/db" => {
+                r#"{"data":{"username":"app","password":"secret","port":5432,"a/b":"slash"}}"#
+            }
+            "/v1/secrets/tokens/api%2Bkey" => r#""abc""#,
+            _ => return (404, "no such secret".to_owned()),
+        };
+        (200, body.to_owned())
+    })
+    .await
+    .unwrap();
+
+    let url = format!("{}v1/secrets/{{path}}", server.url());
+    let mut client = Client::new(url)
+        .header("X-Api-Key", "key123")
+        .unwrap()
+        .pointer("/data/{key}");
+    let sf = Secretfile::from_str(
+        "\
+DB_USERNAME db:username
+DB_PASSWORD db:password
+DB_PORT db:port
+DB_SLASH db:a/b
+DB_MISSING db:missing
+API_TOKEN tokens/api+key
+NOT_FOUND missing
+",
+    )
+    .unwrap();
+    assert_eq!("app", client.var(&sf, "DB_USERNAME").await.unwrap());
+    assert_eq!("secret", client.var(&sf, "DB_PASSWORD").await.unwrap());
+    assert_eq!("5432", client.var(&sf, "DB_PORT").await.unwrap());
+    assert_eq!("slash", client.var(&sf, "DB_SLASH").await.unwrap());
+    assert!(client.var(&sf, "DB_MISSING").await.is_err());
+    assert_eq!("abc", client.var(&sf, "API_TOKEN").await.unwrap());
+    let err = client.var(&sf, "NOT_FOUND").await.unwrap_err();
+    match err {
+        Error::Url { ref source, .. } => match **source {
+            Error::UnexpectedHttpStatus { status, ref body } => {
+                assert_eq!(404, status.as_u16());
+                assert_eq!("no such secret", body);
+            }
+            ref other => panic!("unexpected error: {:?}", other),
+        },
+        ref other => panic!("unexpected error: {:?}", other),
+    }
+
+    // We only fetch each path once.
+    let requests = server.requests();
+    assert_eq!(
+        1,
+        requests
+            .iter()
+            .filter(|r| r.path == "/v1/secrets/db")
+            .count()
+    );
+    assert!(Client::new("http://example.com/{path}")
+        .header("bad header", "x")
+        .is_err());
+}
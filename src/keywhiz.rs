@@ -0,0 +1,291 @@
+//! A backend for Square's Keywhiz.
+//!
+//! Keywhiz identifies secrets using simple names, so this backend handles
+//! `Location::Path` entries in the `Secretfile`, such as:
+//!
+//! ```text
+//! FOO_USERNAME ${SECRET_NAME}_username
+//! ```
+//!
+//! Secrets can be read either from a directory mounted using `keywhiz-fs`,
+//! or directly from a Keywhiz server using mutual TLS.
+
+use reqwest::Url;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use tracing::debug;
+
+use crate::backend::Backend;
+use crate::errors::*;
+use crate::http::{send_json, with_trailing_slash};
+use crate::secretfile::{Location, Secretfile};
+
+/// Secret data retrieved from the Keywhiz client API.
+#[derive(Debug, Deserialize)]
+struct SecretDeliveryResponse {
+    /// The base64-encoded secret.
+    secret: String,
+}
+
+/// Where we fetch our secrets from.
+enum Source {
+    /// A directory mounted using `keywhiz-fs`.
+    Directory(PathBuf),
+    /// A Keywhiz server.
+    Server {
+        /// Our HTTP client, configured with our client certificate.
+        client: reqwest::Client,
+        /// The address of our Keywhiz server.
+        addr: Url,
+    },
+}
+
+/// Fetches credentials from Keywhiz.
+pub struct Client {
+    source: Source,
+}
+
+impl Client {
+    /// Has the user indicated that they want to enable our Keywhiz backend,
+    /// by setting either `KEYWHIZ_FS_DIR` or `KEYWHIZ_ADDR`?
+    pub fn is_enabled() -> bool {
+        env::var_os("KEYWHIZ_FS_DIR").is_some()
+            || env::var_os("KEYWHIZ_ADDR").is_some()
+    }
+
+    /// Construct a new `keywhiz::Client` using environment variables.
+    ///
+    /// If `KEYWHIZ_FS_DIR` is set, we read secrets from that `keywhiz-fs`
+    /// mount.  Otherwise, we connect to the server at `KEYWHIZ_ADDR`,
+    /// authenticating using the PEM files in `KEYWHIZ_CLIENT_CERT` and
+    /// `KEYWHIZ_CLIENT_KEY`.  If `KEYWHIZ_CA_CERT` is set, we verify the
+    /// server using that PEM file, and otherwise we use the root
+    /// certificates enabled in `reqwest`, such as those from `default-tls`.
+    pub fn from_env() -> Result<Client> {
+        if let Some(dir) = env::var_os("KEYWHIZ_FS_DIR") {
+            return Ok(Client::from_directory(dir));
+        }
+        let addr = env::var("KEYWHIZ_ADDR").map_err(|err| {
+            Error::UndefinedEnvironmentVariable {
+                name: "KEYWHIZ_ADDR".to_owned(),
+                source: err,
+            }
+        })?;
+        let cert = read_env_file("KEYWHIZ_CLIENT_CERT")?;
+        let key = read_env_file("KEYWHIZ_CLIENT_KEY")?;
+        let ca_cert = match env::var_os("KEYWHIZ_CA_CERT") {
+            Some(_) => Some(read_env_file("KEYWHIZ_CA_CERT")?),
+            None => None,
+        };
+        let mut identity = key;
+        identity.extend_from_slice(&cert);
+        Client::from_server(addr.parse()?, Some(&identity), ca_cert.as_deref())
+    }
+
+    /// Read secrets from a directory mounted using `keywhiz-fs`.
+    pub fn from_directory<P: Into<PathBuf>>(dir: P) -> Client {
+        Client {
+            source: Source::Directory(dir.into()),
+        }
+    }
+
+    /// Read secrets from the Keywhiz server at `addr`.  `identity` is a PEM
+    /// file containing our private key and client certificate, and
+    /// `ca_cert` is a PEM file containing the CA used to verify the server.
+    /// Secrets are fetched from `<addr>/secret/<name>`, even if `addr` has
+    /// no trailing slash.
+    pub fn from_server(
+        addr: Url,
+        identity: Option<&[u8]>,
+        ca_cert: Option<&[u8]>,
+    ) -> Result<Client> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(identity) = identity {
+            let identity = reqwest::Identity::from_pem(identity)
+                .map_err(|err| Error::Other(err.into()))?;
+            builder = builder.identity(identity);
+        }
+        if let Some(ca_cert) = ca_cert {
+            let ca_cert = reqwest::Certificate::from_pem(ca_cert)
+                .map_err(|err| Error::Other(err.into()))?;
+            builder = builder.add_root_certificate(ca_cert);
+        }
+        let client = builder.build().map_err(|err| Error::Other(err.into()))?;
+        let addr = with_trailing_slash(addr.as_str()).parse()?;
+        Ok(Client {
+            source: Source::Server { client, addr },
+        })
+    }
+
+    /// Fetch a secret by name.
+    async fn get_secret(&self, name: &str) -> Result<String> {
+        match self.source {
+            Source::Directory(ref dir) => {
+                let path = dir.join(checked_name(name)?);
+                let value =
+                    fs::read_to_string(&path).map_err(|err| Error::FileRead {
+                        path: path.clone(),
+                        source: Box::new(err.into()),
+                    })?;
+                debug!("Found credential {} in {}", name, path.display());
+                Ok(value)
+            }
+            Source::Server {
+                ref client,
+                ref addr,
+            } => {
+                let url = addr.join(&format!("secret/{}", checked_name(name)?))?;
+                debug!("Getting secret {}", url);
+
+                let req = client.get(url.clone());
+                let secret: SecretDeliveryResponse = send_json(&url, req).await?;
+                let mkerr = |err| Error::Url {
+                    url: url.clone(),
+                    source: Box::new(err),
+                };
+                let bytes =
+                    base64::decode(&secret.secret).map_err(|err| mkerr(err.into()))?;
+                String::from_utf8(bytes).map_err(|err| mkerr(err.into()))
+            }
+        }
+    }
+
+    async fn get_loc(
+        &self,
+        searched_for: &str,
+        loc: Option<Location>,
+    ) -> Result<String> {
+        match loc {
+            None => Err(Error::MissingEntry {
+                name: searched_for.to_owned(),
+            }),
+            Some(Location::Path(ref name)) => self.get_secret(name).await,
+            Some(Location::PathWithKey(ref path, ref key)) => {
+                Err(Error::UnexpectedKeyInPath {
+                    path: path.to_owned(),
+                    key: key.to_owned(),
+                })
+            }
+        }
+    }
+}
+
+/// Make sure a secret name is a single, ordinary path component, so that it
+/// can't refer to anything outside our secrets directory.
+fn checked_name(name: &str) -> Result<&str> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(name),
+        _ => Err(Error::Parse {
+            input: name.to_owned(),
+        }),
+    }
+}
+
+/// Read the file named by the environment variable `var`.
+fn read_env_file(var: &str) -> Result<Vec<u8>> {
+    let path = env::var(var).map_err(|err| Error::UndefinedEnvironmentVariable {
+        name: var.to_owned(),
+        source: err,
+    })?;
+    fs::read(&path).map_err(|err| Error::FileRead {
+        path: PathBuf::from(path),
+        source: Box::new(err.into()),
+    })
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "keywhiz"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        let loc = secretfile.var(credential).cloned();
+        self.get_loc(credential, loc).await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        let loc = secretfile.file(path).cloned();
+        self.get_loc(path, loc).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::str::FromStr;
+
+    use super::Client;
+    use crate::backend::Backend;
+    use crate::mock_http::MockServer;
+    use crate::secretfile::Secretfile;
+
+    #[tokio::test]
+    async fn test_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("foo_username"), "user").unwrap();
+        let sf = Secretfile::from_str(
+            "FOO_USERNAME foo_username\nFOO_PASSWORD secret/foo:password\nFOO_ESCAPE ../foo_username\n",
+        )
+        .unwrap();
+        let mut client = Client::from_directory(dir.path());
+        assert_eq!("user", client.var(&sf, "FOO_USERNAME").await.unwrap());
+        assert!(client.var(&sf, "FOO_PASSWORD").await.is_err());
+        assert!(client.var(&sf, "FOO_ESCAPE").await.is_err());
+        assert!(client.var(&sf, "NOSUCHVAR").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server() {
+        let server = MockServer::start(|req| match req.path.as_str() {
+            "/secret/foo_username" => (
+                200,
+                r#"{"name":"foo_username","secret":"dXNlcg==","secretLength":4}"#
+                    .to_owned(),
+            ),
+            _ => (404, "not found".to_owned()),
+        })
+        .await
+        .unwrap();
+        let sf =
+            Secretfile::from_str("FOO_USERNAME foo_username\nFOO_TOKEN foo_token\n")
+                .unwrap();
+        let mut client = Client::from_server(server.url(), None, None).unwrap();
+        assert_eq!("user", client.var(&sf, "FOO_USERNAME").await.unwrap());
+        assert!(client.var(&sf, "FOO_TOKEN").await.is_err());
+        assert_eq!(2, server.requests().len());
+    }
+
+    #[tokio::test]
+    async fn test_server_base_path() {
+        let server = MockServer::start(|req| match req.path.as_str() {
+            "/keywhiz/secret/foo_username" => (
+                200,
+                r#"{"name":"foo_username","secret":"dXNlcg==","secretLength":4}"#
+                    .to_owned(),
+            ),
+            _ => (404, "not found".to_owned()),
+        })
+        .await
+        .unwrap();
+        let sf = Secretfile::from_str("FOO_USERNAME foo_username\n").unwrap();
+        let addr = server.url().join("keywhiz").unwrap();
+        let mut client = Client::from_server(addr, None, None).unwrap();
+        assert_eq!("user", client.var(&sf, "FOO_USERNAME").await.unwrap());
+    }
+
+    #[test]
+    fn test_bad_identity() {
+        let addr = "https://keywhiz.example.com/".parse().unwrap();
+        assert!(Client::from_server(addr, Some(b"not a PEM file"), None).is_err());
+    }
+}
@@ -0,0 +1,264 @@
+//! A backend which reads Kubernetes `Secret` objects directly from the API
+//! server, using the in-cluster service account token and CA certificate.
+//!
+//! Entries in the `Secretfile` have the form `namespace/secret-name:key`,
+//! and return the decoded value of `key`.  If the namespace is omitted, we
+//! use the namespace of our own service account:
+//!
+//! ```text
+//! DB_PASSWORD production/db-credentials:password
+//! API_TOKEN api-credentials:token
+//! ```
+//!
+//! Our service account will need permission to `get` these secrets.
+
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+use crate::backend::Backend;
+use crate::errors::*;
+use crate::http::send_json;
+use crate::secretfile::{Location, Secretfile};
+
+/// The directory where Kubernetes mounts our service account credentials.
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// The parts of a Kubernetes `Secret` that we care about.
+#[derive(Deserialize)]
+struct Secret {
+    /// Base64-encoded values.
+    #[serde(default)]
+    data: BTreeMap<String, String>,
+}
+
+/// Fetches credentials from the Kubernetes API server.
+pub struct Client {
+    client: reqwest::Client,
+    /// The address of the API server.
+    addr: Url,
+    /// Where to find our service account token.  We re-read this for each
+    /// request, because Kubernetes rotates it.
+    token_path: PathBuf,
+    /// The namespace to use when none is specified.
+    namespace: String,
+    /// Local cache of secrets.
+    secrets: BTreeMap<String, Secret>,
+}
+
+impl Client {
+    /// Are we running inside a Kubernetes cluster?
+    pub fn is_enabled() -> bool {
+        env::var_os("KUBERNETES_SERVICE_HOST").is_some()
+    }
+
+    /// Construct a new `kubernetes::Client` using the in-cluster
+    /// configuration.
+    pub fn from_env() -> Result<Client> {
+        let host = env::var("KUBERNETES_SERVICE_HOST").map_err(|err| {
+            Error::UndefinedEnvironmentVariable {
+                name: "KUBERNETES_SERVICE_HOST".to_owned(),
+                source: err,
+            }
+        })?;
+        let port =
+            env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_owned());
+        Client::from_service_account(&host, &port, Path::new(SERVICE_ACCOUNT_DIR))
+    }
+
+    /// Construct a new `kubernetes::Client` which talks to the API server
+    /// at `host` and `port`, using the service account mounted at `dir`.
+    pub(crate) fn from_service_account(
+        host: &str,
+        port: &str,
+        dir: &Path,
+    ) -> Result<Client> {
+        // IPv6 addresses need to be wrapped in brackets.
+        let addr = if host.contains(':') {
+            format!("https://[{}]:{}/", host, port)
+        } else {
+            format!("https://{}:{}/", host, port)
+        };
+
+        let ca_cert = read_file(&dir.join("ca.crt"))?;
+        let ca_cert = reqwest::Certificate::from_pem(ca_cert.as_bytes())
+            .map_err(|err| Error::Other(err.into()))?;
+        let client = reqwest::Client::builder()
+            .add_root_certificate(ca_cert)
+            .build()
+            .map_err(|err| Error::Other(err.into()))?;
+        let namespace = read_file(&dir.join("namespace"))?;
+        Ok(Client::new(
+            client,
+            addr.parse()?,
+            dir.join("token"),
+            namespace.trim().to_owned(),
+        ))
+    }
+
+    fn new(
+        client: reqwest::Client,
+        addr: Url,
+        token_path: PathBuf,
+        namespace: String,
+    ) -> Client {
+        Client {
+            client,
+            addr,
+            token_path,
+            namespace,
+            secrets: BTreeMap::new(),
+        }
+    }
+
+    /// Fetch the secret `name` in `namespace`.
+    async fn get_secret(&self, namespace: &str, name: &str) -> Result<Secret> {
+        let token = read_file(&self.token_path)?;
+        let url = self
+            .addr
+            .join(&format!("api/v1/namespaces/{}/secrets/{}", namespace, name))?;
+        debug!("Getting secret {}", url);
+        let req = self.client.get(url.clone()).bearer_auth(token.trim());
+        send_json(&url, req).await
+    }
+
+    async fn get_loc(
+        &mut self,
+        searched_for: &str,
+        loc: Option<Location>,
+    ) -> Result<String> {
+        match loc {
+            None => Err(Error::MissingEntry {
+                name: searched_for.to_owned(),
+            }),
+            Some(Location::PathWithKey(ref path, ref key)) => {
+                let (namespace, name) = match path.split_once('/') {
+                    Some((namespace, name)) => (namespace, name),
+                    None => (self.namespace.as_str(), path.as_str()),
+                };
+                if namespace.is_empty() || name.is_empty() || name.contains('/') {
+                    return Err(Error::Parse {
+                        input: path.to_owned(),
+                    });
+                }
+
+                // Cache secrets, so that related keys are consistent.
+                let cache_key = format!("{}/{}", namespace, name);
+                if !self.secrets.contains_key(&cache_key) {
+                    let secret = self.get_secret(namespace, name).await?;
+                    self.secrets.insert(cache_key.clone(), secret);
+                }
+                let secret = &self.secrets[&cache_key];
+
+                let value =
+                    secret
+                        .data
+                        .get(key)
+                        .ok_or_else(|| Error::MissingKeyInSecret {
+                            secret: path.to_owned(),
+                            key: key.to_owned(),
+                        })?;
+                Ok(String::from_utf8(base64::decode(value)?)?)
+            }
+            Some(Location::Path(ref path)) => Err(Error::MissingKeyInPath {
+                path: path.to_owned(),
+            }),
+        }
+    }
+}
+
+/// Read a file mounted by Kubernetes.
+fn read_file(path: &Path) -> Result<String> {
+    fs::read_to_string(path).map_err(|err| Error::FileRead {
+        path: path.to_owned(),
+        source: Box::new(err.into()),
+    })
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "kubernetes"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        let loc = secretfile.var(credential).cloned();
+        self.get_loc(credential, loc).await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        let loc = secretfile.file(path).cloned();
+        self.get_loc(path, loc).await
+    }
+}
+
+#[tokio::test]
+async fn test_kubernetes() {
+    use crate::mock_http::MockServer;
+    use std::str::FromStr;
+
+    let server = MockServer::start(|req| {
+        if req.header("authorization") != Some("Bearer sa-token") {
+            return (401, r#"{"kind":"Status","code":401}"#.to_owned());
+        }
+        match req.path.as_str() {
+            "/api/v1/namespaces/production/secrets/db-credentials" => (
+                200,
+                r#"{"kind":"Secret","data":{"username":"YXBw","password":"c2VjcmV0"}}"#
+                    .to_owned(),
+            ),
+            "/api/v1/namespaces/default/secrets/api-credentials" => (
+                200,
+                r#"{"kind":"Secret","data":{"token":"YWJj"}}"#.to_owned(),
+            ),
+            _ => (
+                404,
+                r#"{"kind":"Status","reason":"NotFound","code":404}"#.to_owned(),
+            ),
+        }
+    })
+    .await
+    .unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let token_path = dir.path().join("token");
+    fs::write(&token_path, "sa-token\n").unwrap();
+    let mut client = Client::new(
+        reqwest::Client::new(),
+        server.url(),
+        token_path,
+        "default".to_owned(),
+    );
+
+    let sf = Secretfile::from_str(
+        "\
+DB_USERNAME production/db-credentials:username
+DB_PASSWORD production/db-credentials:password
+API_TOKEN api-credentials:token
+MISSING_KEY api-credentials:missing
+MISSING_SECRET production/missing:key
+NO_KEY production/db-credentials
+",
+    )
+    .unwrap();
+    assert_eq!("app", client.var(&sf, "DB_USERNAME").await.unwrap());
+    assert_eq!("secret", client.var(&sf, "DB_PASSWORD").await.unwrap());
+    assert_eq!("abc", client.var(&sf, "API_TOKEN").await.unwrap());
+    assert!(client.var(&sf, "MISSING_KEY").await.is_err());
+    let err = client.var(&sf, "MISSING_SECRET").await.unwrap_err();
+    assert!(format!("{:?}", err).contains("NotFound"));
+    assert!(client.var(&sf, "NO_KEY").await.is_err());
+
+    // We only fetch each secret once.
+    assert_eq!(3, server.requests().len());
+}
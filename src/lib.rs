@@ -17,7 +17,6 @@
 #![warn(missing_docs)]
 #![allow(clippy::redundant_closure)]
 
-use backend::Backend;
 use lazy_static::lazy_static;
 use std::convert::AsRef;
 use std::default::Default;
@@ -28,23 +27,79 @@ use std::sync::Arc;
 use tokio::sync::Mutex;
 use tracing::trace;
 
-// Be very careful not to export any more of the Secretfile API than
-// strictly necessary, because we don't want to stablize too much at this
-// point.
+// We export enough of the Secretfile API to inspect entries and to write
+// custom backends, but be very careful not to export any more than that,
+// because we don't want to stablize too much at this point.
+pub use async_trait::async_trait;
+pub use backend::Backend;
 pub use errors::{Error, Result};
-pub use secretfile::{Secretfile, SecretfileKeys};
+pub use secretfile::{
+    EntryOptions, Location, Secretfile, SecretfileBuilder, SecretfileKeys,
+    SecretfileLocations, Transform,
+};
 
+#[cfg(feature = "age")]
+pub mod age;
+#[cfg(any(feature = "aws-secrets-manager", feature = "aws-ssm"))]
+pub mod aws;
+#[cfg(feature = "azure")]
+pub mod azure;
 mod backend;
 mod chained;
+#[cfg(feature = "command")]
+pub mod command;
+#[cfg(feature = "consul")]
+pub mod consul;
+pub mod directory;
+pub mod dotenv;
 mod envvar;
 mod errors;
+#[cfg(feature = "gcp")]
+pub mod gcp;
+mod http;
+#[cfg(feature = "http-json")]
+pub mod http_json;
+#[cfg(feature = "keywhiz")]
+pub mod keywhiz;
+#[cfg(feature = "kubernetes")]
+pub mod kubernetes;
+#[cfg(any(
+    feature = "testing",
+    all(
+        test,
+        any(
+            feature = "aws-secrets-manager",
+            feature = "aws-ssm",
+            feature = "azure",
+            feature = "consul",
+            feature = "gcp",
+            feature = "http-json",
+            feature = "keywhiz",
+            feature = "kubernetes",
+            feature = "onepassword"
+        )
+    )
+))]
+mod mock_http;
+#[cfg(feature = "onepassword")]
+pub mod onepassword;
+#[cfg(feature = "pass")]
+pub mod pass;
+#[cfg(feature = "secret-service")]
+pub mod secret_service;
 mod secretfile;
-mod vault;
+#[cfg(feature = "sops")]
+pub mod sops;
+pub mod systemd;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod vault;
 
 /// Options which can be passed to `Client::new`.
 pub struct Options {
     secretfile: Option<Secretfile>,
     allow_override: bool,
+    backends: Option<Vec<Box<dyn Backend>>>,
 }
 
 impl Default for Options {
@@ -54,6 +109,7 @@ impl Default for Options {
         Options {
             secretfile: None,
             allow_override: true,
+            backends: None,
         }
     }
 }
@@ -68,11 +124,32 @@ impl Options {
     }
 
     /// Allow secrets in environment variables and local files to override
-    /// the ones specified in our `Secretfile`.  Defaults to true.
+    /// the ones specified in our `Secretfile`.  Defaults to true.  This is
+    /// ignored if you specify your own `backends`.
     pub fn allow_override(mut self, allow_override: bool) -> Options {
         self.allow_override = allow_override;
         self
     }
+
+    /// Use the specified backends, in order, instead of choosing them
+    /// automatically.  The first backend to return a value wins.
+    ///
+    /// ```
+    /// # #[tokio::main]
+    /// # async fn main() -> credentials::Result<()> {
+    /// use credentials::{directory, Client, Options, Secretfile};
+    ///
+    /// let options = Options::default()
+    ///     .secretfile(Secretfile::builder().build()?)
+    ///     .backends(vec![Box::new(directory::Client::new("/run/secrets"))]);
+    /// let client = Client::new(options).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn backends(mut self, backends: Vec<Box<dyn Backend>>) -> Options {
+        self.backends = Some(backends);
+        self
+    }
 }
 
 /// A client which fetches secrets.  Under normal circumstances, it's
@@ -91,10 +168,16 @@ impl Client {
             Some(sf) => sf,
             None => Secretfile::default()?,
         };
-        let over = options.allow_override;
+        let backend = match options.backends {
+            Some(backends) => chained::Client::with_backends(backends),
+            None => {
+                let over = options.allow_override;
+                chained::Client::with_default_backends(over).await?
+            }
+        };
         Ok(Client {
             secretfile,
-            backend: chained::Client::with_default_backends(over).await?,
+            backend,
         })
     }
 
@@ -117,9 +200,16 @@ impl Client {
     pub async fn var<S: AsRef<str>>(&mut self, name: S) -> Result<String> {
         let name_ref = name.as_ref();
         trace!("getting secure credential {}", name_ref);
+        let options = self
+            .secretfile
+            .var_options(name_ref)
+            .cloned()
+            .unwrap_or_default();
         self.backend
-            .var(&self.secretfile, name_ref)
+            .var_from(&self.secretfile, name_ref)
             .await
+            .and_then(|(value, backend)| apply_options(&options, value, backend))
+            .and_then(|bytes| Ok(String::from_utf8(bytes)?))
             .map_err(|err| Error::Credential {
                 name: name_ref.to_owned(),
                 source: Box::new(err),
@@ -128,6 +218,18 @@ impl Client {
 
     /// Fetch the value of a file-style credential.
     pub async fn file<S: AsRef<Path>>(&mut self, path: S) -> Result<String> {
+        let path_ref = path.as_ref();
+        let bytes = self.file_bytes(path_ref).await?;
+        String::from_utf8(bytes).map_err(|err| Error::Credential {
+            name: format!("{}", path_ref.display()),
+            source: Box::new(err.into()),
+        })
+    }
+
+    /// Fetch the value of a file-style credential as raw bytes.  This is
+    /// useful for binary credentials, such as base64-encoded keystores
+    /// decoded using `|base64`.
+    pub async fn file_bytes<S: AsRef<Path>>(&mut self, path: S) -> Result<Vec<u8>> {
         let path_ref = path.as_ref();
         let path_str = path_ref.to_str().ok_or_else(|| Error::Credential {
             name: format!("{}", path_ref.display()),
@@ -136,9 +238,15 @@ impl Client {
             }),
         })?;
         trace!("getting secure credential {}", path_str);
+        let options = self
+            .secretfile
+            .file_options(path_str)
+            .cloned()
+            .unwrap_or_default();
         self.backend
-            .file(&self.secretfile, path_str)
+            .file_from(&self.secretfile, path_str)
             .await
+            .and_then(|(value, backend)| apply_options(&options, value, backend))
             .map_err(|err| Error::Credential {
                 name: path_str.to_owned(),
                 source: Box::new(err),
@@ -146,6 +254,20 @@ impl Client {
     }
 }
 
+/// Apply the transforms in `options` to a `value` returned by `backend`.
+/// Values from environment variables and other overrides are used as is.
+fn apply_options(
+    options: &EntryOptions,
+    value: String,
+    backend: &str,
+) -> Result<Vec<u8>> {
+    if chained::is_override(backend) {
+        Ok(value.into_bytes())
+    } else {
+        options.apply(value)
+    }
+}
+
 lazy_static! {
     // Our shared global client, initialized by `lazy_static!` and
     // protected by a Mutex.
@@ -161,18 +283,26 @@ lazy_static! {
         Arc::new(Mutex::new(None));
 }
 
+/// Replace the shared global client used by `credentials::var` and
+/// `credentials::file`.  This allows applications to configure custom
+/// `Options`, such as their own `backends`.  Passing `None` discards the
+/// current client, and a new default client will be created when next
+/// needed.
+pub async fn set_global_client(client: Option<Client>) {
+    *CLIENT.lock().await = client;
+}
+
 /// Call `body` with the default global client, or return an error if we can't
 /// allocate a default global client.
 ///
 /// `F` has a rather horrible type constraint that allows it to hold onto a
 /// `&mut` pointing at the contents of `client_cell`. See
 /// https://users.rust-lang.org/t/function-that-takes-a-closure-with-mutable-reference-that-returns-a-future/54324.
-async fn with_client<F>(body: F) -> Result<String>
+async fn with_client<F, T>(body: F) -> Result<T>
 where
     F: for<'a> FnOnce(
         &'a mut Client,
-    )
-        -> Pin<Box<dyn Future<Output = Result<String>> + Send + 'a>>,
+    ) -> Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>,
 {
     let mut client_cell = CLIENT.clone().lock_owned().await;
 
@@ -204,6 +334,12 @@ pub async fn file<S: AsRef<Path>>(path: S) -> Result<String> {
     with_client(|client| Box::pin(client.file(path))).await
 }
 
+/// Fetch the value of a file-style credential as raw bytes.
+pub async fn file_bytes<S: AsRef<Path>>(path: S) -> Result<Vec<u8>> {
+    let path = path.as_ref().to_owned();
+    with_client(|client| Box::pin(client.file_bytes(path))).await
+}
+
 #[cfg(test)]
 mod test {
     use super::file;
@@ -221,4 +357,84 @@ mod test {
         assert_eq!(expected, file(&Path::new("Cargo.toml")).await.unwrap());
         assert!(file(&Path::new("nosuchfile.txt")).await.is_err());
     }
+
+    #[tokio::test]
+    async fn test_custom_backends() {
+        use super::{Backend, Client, Error, Options, Result, Secretfile};
+
+        struct Constant;
+
+        #[async_trait::async_trait]
+        impl Backend for Constant {
+            fn name(&self) -> &'static str {
+                "constant"
+            }
+
+            async fn var(
+                &mut self,
+                _sf: &Secretfile,
+                _credential: &str,
+            ) -> Result<String> {
+                Ok("constant".to_owned())
+            }
+
+            async fn file(&mut self, _sf: &Secretfile, _path: &str) -> Result<String> {
+                Err(Error::Other("no files".into()))
+            }
+        }
+
+        let options = Options::default()
+            .secretfile(Secretfile::builder().build().unwrap())
+            .backends(vec![Box::new(Constant)]);
+        let mut client = Client::new(options).await.unwrap();
+        assert_eq!("constant", client.var("PATH").await.unwrap());
+        assert!(client.file("Cargo.toml").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transforms() {
+        use super::{Backend, Client, Options, Result, Secretfile};
+        use std::env;
+
+        struct Encoded(&'static str);
+
+        #[async_trait::async_trait]
+        impl Backend for Encoded {
+            fn name(&self) -> &'static str {
+                "encoded"
+            }
+
+            async fn var(
+                &mut self,
+                _sf: &Secretfile,
+                _credential: &str,
+            ) -> Result<String> {
+                Ok(self.0.to_owned())
+            }
+
+            async fn file(&mut self, _sf: &Secretfile, _path: &str) -> Result<String> {
+                Ok(self.0.to_owned())
+            }
+        }
+
+        let sf = Secretfile::from_toml(
+            "[vars]\nTRANSFORMED = { path = \"secret/encoded\", transforms = [\"base64\"] }\n",
+        )
+        .unwrap();
+        let client = |value| {
+            let options = Options::default()
+                .secretfile(sf.clone())
+                .backends(vec![Box::new(Encoded(value))]);
+            Client::new(options)
+        };
+        let mut encoded = client("c2VjcmV0Cg==").await.unwrap();
+        assert_eq!("secret\n", encoded.var("TRANSFORMED").await.unwrap());
+        let mut bad = client("not base64!").await.unwrap();
+        assert!(bad.var("TRANSFORMED").await.is_err());
+
+        // Values which override the `Secretfile` are used as is.
+        let mut client = Client::with_secretfile(sf).await.unwrap();
+        env::set_var("TRANSFORMED", "plain");
+        assert_eq!("plain", client.var("TRANSFORMED").await.unwrap());
+    }
 }
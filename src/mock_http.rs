@@ -0,0 +1,166 @@
+//! A tiny HTTP server for testing backends which talk to HTTP APIs.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A request received by a `MockServer`.  Each backend's tests look at
+/// different parts of it, so some of them may be unused.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    /// The path and query string.
+    pub(crate) path: String,
+    /// Headers, with lowercase names.
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    /// Look up a header by its lowercase name.
+    #[allow(dead_code)]
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Our request handler, which returns an HTTP status, a JSON body, and how
+/// long to wait before responding.
+type Handler = dyn Fn(&Request) -> (u16, String, Duration) + Send + Sync;
+
+/// An HTTP server which answers requests on localhost using a handler
+/// function, and which records every request it receives.
+pub(crate) struct MockServer {
+    addr: SocketAddr,
+    // Not every user of `MockServer` looks at the requests it received.
+    #[allow(dead_code)]
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl MockServer {
+    /// Start a new server in the background.  `MockVault` only uses
+    /// `start_with_delays`.
+    #[allow(dead_code)]
+    pub(crate) async fn start<F>(handler: F) -> io::Result<MockServer>
+    where
+        F: Fn(&Request) -> (u16, String) + Send + Sync + 'static,
+    {
+        MockServer::start_with_delays(move |req| {
+            let (status, body) = handler(req);
+            (status, body, Duration::ZERO)
+        })
+        .await
+    }
+
+    /// Start a new server in the background, using a handler which can
+    /// also delay its responses.
+    pub(crate) async fn start_with_delays<F>(handler: F) -> io::Result<MockServer>
+    where
+        F: Fn(&Request) -> (u16, String, Duration) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let requests = Arc::new(Mutex::new(vec![]));
+        let handler: Arc<Handler> = Arc::new(handler);
+        let server_requests = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                let requests = server_requests.clone();
+                tokio::spawn(async move {
+                    if let Some(req) = read_request(&mut stream).await {
+                        // Record the request before we reply, so that it's
+                        // visible as soon as the client gets a response.
+                        requests.lock().unwrap().push(req.clone());
+                        let _ = respond(&mut stream, &*handler, &req).await;
+                    }
+                });
+            }
+        });
+        Ok(MockServer { addr, requests })
+    }
+
+    /// The base URL of this server, with a trailing slash.
+    pub(crate) fn url(&self) -> reqwest::Url {
+        format!("http://{}/", self.addr).parse().unwrap()
+    }
+
+    /// All the requests we've received so far.
+    #[allow(dead_code)]
+    pub(crate) fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Read a single request from `stream`.
+async fn read_request(stream: &mut TcpStream) -> Option<Request> {
+    let mut buf = vec![];
+    let header_end = loop {
+        let mut chunk = [0; 4096];
+        let count = stream.read(&mut chunk).await.ok()?;
+        if count == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..count]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_owned();
+    let path = request_line.next()?.to_owned();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(n, v)| (n.trim().to_ascii_lowercase(), v.trim().to_owned()))
+        .collect::<Vec<_>>();
+    let len = headers
+        .iter()
+        .find(|(n, _)| n == "content-length")
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = buf[header_end..].to_vec();
+    while body.len() < len {
+        let mut chunk = [0; 4096];
+        let count = stream.read(&mut chunk).await.ok()?;
+        if count == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..count]);
+    }
+
+    Some(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+/// Reply to `req` using `handler`.
+async fn respond(
+    stream: &mut TcpStream,
+    handler: &Handler,
+    req: &Request,
+) -> io::Result<()> {
+    let (status, body, delay) = handler(req);
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+    let response = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await
+}
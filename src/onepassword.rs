@@ -0,0 +1,279 @@
+//! A backend which reads secrets from a [1Password Connect][connect]
+//! server.
+//!
+//! Entries in the `Secretfile` have the form `vault/item:field`, where each
+//! part may be either a name or an ID:
+//!
+//! ```text
+//! DB_PASSWORD Production/Database:password
+//! ```
+//!
+//! The server is specified using `OP_CONNECT_HOST`, and we authenticate
+//! using `OP_CONNECT_TOKEN`.  We cache the IDs of vaults and items, so
+//! each is only looked up once.
+//!
+//! [connect]: https://developer.1password.com/docs/connect/
+
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use tracing::debug;
+
+use crate::backend::Backend;
+use crate::errors::*;
+use crate::http::send_json;
+use crate::secretfile::{Location, Secretfile};
+
+/// A vault or item, as returned by a list request.
+#[derive(Deserialize)]
+struct Summary {
+    id: String,
+}
+
+/// An item, including its fields.
+#[derive(Deserialize)]
+struct Item {
+    #[serde(default)]
+    fields: Vec<Field>,
+}
+
+/// A field in an item.
+#[derive(Deserialize)]
+struct Field {
+    id: String,
+    label: Option<String>,
+    value: Option<String>,
+}
+
+/// Fetches credentials from 1Password Connect.
+pub struct Client {
+    client: reqwest::Client,
+    /// The address of our Connect server.
+    addr: Url,
+    /// The token we use to authenticate.
+    token: String,
+    /// Maps vault names to IDs.
+    vault_ids: BTreeMap<String, String>,
+    /// Maps `(vault ID, item name)` to item IDs.
+    item_ids: BTreeMap<(String, String), String>,
+    /// Local cache of items, indexed by `(vault ID, item ID)`.
+    items: BTreeMap<(String, String), Item>,
+}
+
+impl Client {
+    /// Has the user specified a server using `OP_CONNECT_HOST`?
+    pub fn is_enabled() -> bool {
+        env::var_os("OP_CONNECT_HOST").is_some()
+    }
+
+    /// Construct a new `onepassword::Client` using `OP_CONNECT_HOST` and
+    /// `OP_CONNECT_TOKEN`.
+    pub fn from_env() -> Result<Client> {
+        let var = |name: &str| {
+            env::var(name).map_err(|err| Error::UndefinedEnvironmentVariable {
+                name: name.to_owned(),
+                source: err,
+            })
+        };
+        let mut addr = var("OP_CONNECT_HOST")?;
+        if !addr.ends_with('/') {
+            addr.push('/');
+        }
+        Ok(Client::new(addr.parse()?, var("OP_CONNECT_TOKEN")?))
+    }
+
+    /// Construct a new `onepassword::Client` which talks to the server at
+    /// `addr`, authenticating with `token`.
+    pub fn new<S: Into<String>>(addr: Url, token: S) -> Client {
+        Client {
+            client: reqwest::Client::new(),
+            addr,
+            token: token.into(),
+            vault_ids: BTreeMap::new(),
+            item_ids: BTreeMap::new(),
+            items: BTreeMap::new(),
+        }
+    }
+
+    /// Make an authenticated `GET` request to `path`.
+    async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        filter: Option<String>,
+    ) -> Result<T> {
+        let mut url = self.addr.join(path)?;
+        if let Some(filter) = filter {
+            url.query_pairs_mut().append_pair("filter", &filter);
+        }
+        debug!("Getting {}", url);
+        let req = self.client.get(url.clone()).bearer_auth(&self.token);
+        send_json(&url, req).await
+    }
+
+    /// Look up the ID of the vault `name`, which may already be an ID.
+    async fn vault_id(&mut self, name: &str) -> Result<String> {
+        if let Some(id) = self.vault_ids.get(name) {
+            return Ok(id.clone());
+        }
+        let filter = format!("name eq {}", quote(name));
+        let vaults: Vec<Summary> = self.get("v1/vaults", Some(filter)).await?;
+        let id = match vaults.into_iter().next() {
+            Some(vault) => vault.id,
+            None => name.to_owned(),
+        };
+        self.vault_ids.insert(name.to_owned(), id.clone());
+        Ok(id)
+    }
+
+    /// Look up the ID of the item `name` in `vault_id`, which may already be
+    /// an ID.
+    async fn item_id(&mut self, vault_id: &str, name: &str) -> Result<String> {
+        let cache_key = (vault_id.to_owned(), name.to_owned());
+        if let Some(id) = self.item_ids.get(&cache_key) {
+            return Ok(id.clone());
+        }
+        let filter = format!("title eq {}", quote(name));
+        let path = format!("v1/vaults/{}/items", vault_id);
+        let items: Vec<Summary> = self.get(&path, Some(filter)).await?;
+        let id = match items.into_iter().next() {
+            Some(item) => item.id,
+            None => name.to_owned(),
+        };
+        self.item_ids.insert(cache_key, id.clone());
+        Ok(id)
+    }
+
+    async fn get_loc(
+        &mut self,
+        searched_for: &str,
+        loc: Option<Location>,
+    ) -> Result<String> {
+        match loc {
+            None => Err(Error::MissingEntry {
+                name: searched_for.to_owned(),
+            }),
+            Some(Location::PathWithKey(ref path, ref key)) => {
+                let (vault, item) =
+                    path.split_once('/').ok_or_else(|| Error::Parse {
+                        input: path.to_owned(),
+                    })?;
+                let vault_id = self.vault_id(vault).await?;
+                let item_id = self.item_id(&vault_id, item).await?;
+
+                let cache_key = (vault_id, item_id);
+                if !self.items.contains_key(&cache_key) {
+                    let path =
+                        format!("v1/vaults/{}/items/{}", cache_key.0, cache_key.1);
+                    let item = self.get(&path, None).await?;
+                    self.items.insert(cache_key.clone(), item);
+                }
+                let item = &self.items[&cache_key];
+
+                item.fields
+                    .iter()
+                    .find(|f| f.label.as_deref() == Some(key))
+                    .or_else(|| item.fields.iter().find(|f| &f.id == key))
+                    .and_then(|f| f.value.clone())
+                    .ok_or_else(|| Error::MissingKeyInSecret {
+                        secret: path.to_owned(),
+                        key: key.to_owned(),
+                    })
+            }
+            Some(Location::Path(ref path)) => Err(Error::MissingKeyInPath {
+                path: path.to_owned(),
+            }),
+        }
+    }
+}
+
+/// Quote `s` for use in a Connect filter expression.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "1password"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        let loc = secretfile.var(credential).cloned();
+        self.get_loc(credential, loc).await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        let loc = secretfile.file(path).cloned();
+        self.get_loc(path, loc).await
+    }
+}
+
+#[tokio::test]
+async fn test_onepassword() {
+    use crate::mock_http::MockServer;
+    use std::str::FromStr;
+
+    let server = MockServer::start(|req| {
+        if req.header("authorization") != Some("Bearer op-token") {
+            return (401, r#"{"status":401,"message":"Invalid token"}"#.to_owned());
+        }
+        let body = match req.path.as_str() {
+            "/v1/vaults?filter=name+eq+%22Production%22" => r#"[{"id":"vault1","name":"Production"}]"#,
+            "/v1/vaults?filter=name+eq+%22vault1%22" => "[]",
+            "/v1/vaults/vault1/items?filter=title+eq+%22Database%22" => {
+                r#"[{"id":"item1","title":"Database"}]"#
+            }
+            "/v1/vaults/vault1/items/item1" => {
+                r#"{"id":"item1","title":"Database","fields":[
+                    {"id":"username","label":"username","value":"app"},
+                    {"id":"password","label":"password","value":"secret","purpose":"PASSWORD"},
+                    {"id":"abc123","label":"API Key","value":"xyz"},
+                    {"id":"notes","label":"notes"}
+                ]}"#
+            }
+            _ => return (404, r#"{"status":404,"message":"Not found"}"#.to_owned()),
+        };
+        (200, body.to_owned())
+    })
+    .await
+    .unwrap();
+
+    let mut client = Client::new(server.url(), "op-token");
+    let sf = Secretfile::from_str(
+        "\
+DB_USERNAME Production/Database:username
+DB_PASSWORD Production/Database:password
+API_KEY Production/Database:abc123
+BY_ID vault1/Database:password
+NOTES Production/Database:notes
+MISSING_ITEM Production/Missing:password
+NO_KEY Production/Database
+",
+    )
+    .unwrap();
+    assert_eq!("app", client.var(&sf, "DB_USERNAME").await.unwrap());
+    assert_eq!("secret", client.var(&sf, "DB_PASSWORD").await.unwrap());
+    assert_eq!("xyz", client.var(&sf, "API_KEY").await.unwrap());
+    assert_eq!("secret", client.var(&sf, "BY_ID").await.unwrap());
+    assert!(client.var(&sf, "NOTES").await.is_err());
+    assert!(client.var(&sf, "MISSING_ITEM").await.is_err());
+    assert!(client.var(&sf, "NO_KEY").await.is_err());
+
+    // We looked up each vault, item and label once.
+    let requests = server.requests();
+    let count = |path: &str| requests.iter().filter(|r| r.path == path).count();
+    assert_eq!(1, count("/v1/vaults?filter=name+eq+%22Production%22"));
+    assert_eq!(
+        1,
+        count("/v1/vaults/vault1/items?filter=title+eq+%22Database%22")
+    );
+    assert_eq!(1, count("/v1/vaults/vault1/items/item1"));
+}
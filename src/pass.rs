@@ -0,0 +1,221 @@
+//! A backend which reads secrets from [pass][], the standard Unix password
+//! manager, by decrypting entries using the local `gpg` binary.
+//!
+//! Because this runs `gpg`, which may prompt for a passphrase, it's only
+//! enabled when `CREDENTIALS_PASS` is set.
+//!
+//! `Location::Path` entries in the `Secretfile` return the entire contents
+//! of `<store>/<path>.gpg`.  `Location::PathWithKey` entries select a
+//! `key: value` line from the entry, except for the key `password`, which
+//! selects the first line, following the conventions of `pass` itself:
+//!
+//! ```text
+//! GITHUB_TOKEN github.com/api:password
+//! GITHUB_USER github.com/api:login
+//! ```
+//!
+//! [pass]: https://www.passwordstore.org/
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::Command;
+use tracing::debug;
+
+use crate::backend::Backend;
+use crate::directory;
+use crate::errors::*;
+use crate::secretfile::{Location, Secretfile};
+
+/// Fetches credentials from a `pass` password store.
+pub struct Client {
+    store: directory::Client,
+    gpg: OsString,
+}
+
+impl Client {
+    /// Has the user indicated that they want to enable our `pass` backend,
+    /// by setting `CREDENTIALS_PASS`?
+    pub fn is_enabled() -> bool {
+        env::var_os("CREDENTIALS_PASS").is_some()
+    }
+
+    /// Construct a new `pass::Client` which reads from the password store
+    /// in `PASSWORD_STORE_DIR`, or in `~/.password-store` by default.
+    pub fn from_env() -> Result<Client> {
+        Ok(Client::new(default_store()?))
+    }
+
+    /// Construct a new `pass::Client` which reads from the password store
+    /// in `store`.
+    pub fn new<P: Into<PathBuf>>(store: P) -> Client {
+        Client {
+            store: directory::Client::new(store),
+            gpg: OsString::from("gpg"),
+        }
+    }
+
+    /// Use the specified `gpg` program instead of the one in our `PATH`.
+    pub fn gpg_program<P: Into<OsString>>(mut self, gpg: P) -> Client {
+        self.gpg = gpg.into();
+        self
+    }
+
+    /// Decrypt the entry at `path`, relative to our store.
+    async fn decrypt(&self, path: &str) -> Result<String> {
+        let path = self.store.resolve(Path::new(&format!("{}.gpg", path)))?;
+        let mkerr = |err| Error::FileRead {
+            path: path.clone(),
+            source: Box::new(err),
+        };
+        // Don't bother running `gpg` for entries which don't exist.
+        fs::metadata(&path).map_err(|err| mkerr(err.into()))?;
+        let output = Command::new(&self.gpg)
+            .args(["--quiet", "--yes", "--batch", "--use-agent", "--decrypt"])
+            .arg(&path)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|err| mkerr(err.into()))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(mkerr(Error::Decrypt(
+                format!("gpg failed with {}: {}", output.status, stderr.trim()).into(),
+            )));
+        }
+        debug!("Decrypted credential in {}", path.display());
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    async fn get_loc(
+        &self,
+        searched_for: &str,
+        loc: Option<&Location>,
+    ) -> Result<String> {
+        match loc {
+            None => Err(Error::MissingEntry {
+                name: searched_for.to_owned(),
+            }),
+            Some(Location::Path(ref path)) => {
+                let body = self.decrypt(path).await?;
+                Ok(body.trim_end_matches(&['\r', '\n'][..]).to_owned())
+            }
+            Some(Location::PathWithKey(ref path, ref key)) => {
+                let body = self.decrypt(path).await?;
+                find_key(&body, key).ok_or_else(|| Error::MissingKeyInSecret {
+                    secret: path.to_owned(),
+                    key: key.to_owned(),
+                })
+            }
+        }
+    }
+}
+
+/// Where should we look for the password store by default?
+fn default_store() -> Result<PathBuf> {
+    match env::var_os("PASSWORD_STORE_DIR") {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => Ok(dirs::home_dir()
+            .ok_or(Error::NoHomeDirectory)?
+            .join(".password-store")),
+    }
+}
+
+/// Look up `key` in the body of a `pass` entry.
+fn find_key(body: &str, key: &str) -> Option<String> {
+    let mut lines = body.lines();
+    if key == "password" {
+        return lines.next().map(|line| line.to_owned());
+    }
+    lines.skip(1).find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        if k.trim() == key {
+            Some(v.trim().to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "pass"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        self.get_loc(credential, secretfile.var(credential)).await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        self.get_loc(path, secretfile.file(path)).await
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_pass() {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::str::FromStr;
+
+    let dir = tempfile::tempdir().unwrap();
+
+    // A stand-in for `gpg` which "decrypts" files by printing them.
+    let gpg = dir.path().join("fake-gpg");
+    fs::write(
+        &gpg,
+        "#!/bin/sh\nfor last; do :; done\nexec cat \"$last\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(&gpg, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let store = dir.path().join("store");
+    fs::create_dir_all(store.join("github.com")).unwrap();
+    fs::write(
+        store.join("github.com/api.gpg"),
+        "hunter2\nlogin: octocat\nurl: https://github.com/\n",
+    )
+    .unwrap();
+
+    let sf = Secretfile::from_str(
+        "\
+GITHUB_TOKEN github.com/api:password
+GITHUB_USER github.com/api:login
+GITHUB_URL github.com/api:url
+GITHUB_MISSING github.com/api:missing
+NO_SUCH_ENTRY github.com/other:password
+ESCAPE ../store/github.com/api:password
+>api.txt github.com/api
+",
+    )
+    .unwrap();
+    let mut client = Client::new(&store).gpg_program(&gpg);
+    assert_eq!("hunter2", client.var(&sf, "GITHUB_TOKEN").await.unwrap());
+    assert_eq!("octocat", client.var(&sf, "GITHUB_USER").await.unwrap());
+    assert_eq!(
+        "https://github.com/",
+        client.var(&sf, "GITHUB_URL").await.unwrap()
+    );
+    assert_eq!(
+        "hunter2\nlogin: octocat\nurl: https://github.com/",
+        client.file(&sf, "api.txt").await.unwrap()
+    );
+    assert!(client.var(&sf, "GITHUB_MISSING").await.is_err());
+    match client.var(&sf, "NO_SUCH_ENTRY").await.unwrap_err() {
+        Error::FileRead { source, .. } => match *source {
+            Error::Io(ref err) => assert_eq!(std::io::ErrorKind::NotFound, err.kind()),
+            ref other => panic!("unexpected error: {:?}", other),
+        },
+        other => panic!("unexpected error: {:?}", other),
+    }
+    assert!(client.var(&sf, "ESCAPE").await.is_err());
+}
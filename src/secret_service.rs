@@ -0,0 +1,491 @@
+//! A backend which reads and writes secrets using the freedesktop.org
+//! [Secret Service API][api], which is provided by GNOME Keyring and
+//! KWallet.
+//!
+//! This backend is only enabled when `CREDENTIALS_SECRET_SERVICE` is set,
+//! because most desktop sessions have a Secret Service, and we don't want
+//! to search it unless asked.
+//!
+//! Items are found using their attributes.  All our items have the
+//! attribute `application` set to `credentials`, and both `path` and `key`
+//! attributes.  `Location::PathWithKey` entries in the `Secretfile` look for
+//! an item with matching `path` and `key` attributes:
+//!
+//! ```text
+//! GITHUB_TOKEN github:token
+//! ```
+//!
+//! Such an item can be created using `secret-tool store --label=GitHub
+//! application credentials path github key token`, or using
+//! `Client::set_var`.  `Location::Path` entries look for an item whose `key`
+//! attribute is empty, so that they never match an item created for a
+//! specific key.
+//!
+//! We never prompt the user to unlock a collection, so items in a locked
+//! collection can't be read.
+//!
+//! [api]: https://specifications.freedesktop.org/secret-service/latest/
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use tracing::debug;
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+use zbus::Connection;
+
+use crate::backend::Backend;
+use crate::errors::*;
+use crate::secretfile::{Location, Secretfile};
+
+/// The bus name of the Secret Service.
+const SERVICE_NAME: &str = "org.freedesktop.secrets";
+
+/// The object path of the Secret Service.
+const SERVICE_PATH: &str = "/org/freedesktop/secrets";
+
+/// The interface implemented by the Secret Service.
+const SERVICE_INTERFACE: &str = "org.freedesktop.Secret.Service";
+
+/// The default collection, where we store new items.
+const DEFAULT_COLLECTION_PATH: &str = "/org/freedesktop/secrets/aliases/default";
+
+/// The interface implemented by collections.
+const COLLECTION_INTERFACE: &str = "org.freedesktop.Secret.Collection";
+
+/// The value of the `application` attribute on all our items.
+const APPLICATION: &str = "credentials";
+
+/// A secret as represented by the Secret Service: `(session, parameters,
+/// value, content_type)`.
+type RawSecret = (OwnedObjectPath, Vec<u8>, Vec<u8>, String);
+
+/// A connection to the Secret Service, with an open session.
+struct Session {
+    connection: Connection,
+    path: OwnedObjectPath,
+}
+
+/// Fetches credentials from the Secret Service over D-Bus.
+#[derive(Default)]
+pub struct Client {
+    /// Our session, which we open the first time we need it.
+    session: Option<Session>,
+}
+
+impl Client {
+    /// Has the user indicated that they want to enable our Secret Service
+    /// backend, by setting `CREDENTIALS_SECRET_SERVICE`?
+    pub fn is_enabled() -> bool {
+        env::var_os("CREDENTIALS_SECRET_SERVICE").is_some()
+    }
+
+    /// Construct a new `secret_service::Client`.  We don't connect to the
+    /// session bus until we're first used.
+    pub fn new() -> Client {
+        Client { session: None }
+    }
+
+    /// Construct a new `secret_service::Client` using an existing
+    /// connection.
+    #[cfg(test)]
+    pub(crate) fn from_connection(connection: Connection) -> Client {
+        Client {
+            session: Some(Session {
+                connection,
+                path: OwnedObjectPath::try_from("/").unwrap(),
+            }),
+        }
+    }
+
+    /// Connect to the Secret Service and open a session, if we haven't
+    /// already.
+    async fn session(&mut self) -> Result<&Session> {
+        if self.session.is_none() {
+            let connection = Connection::session().await?;
+            self.session = Some(Session {
+                connection,
+                path: OwnedObjectPath::try_from("/").unwrap(),
+            });
+        }
+        let session = self.session.as_mut().expect("session should exist");
+        if session.path.as_str() == "/" {
+            // We use the "plain" algorithm, because our secrets never leave
+            // this machine, and the session bus is private to the user.
+            let (_, path): (OwnedValue, OwnedObjectPath) = call(
+                session,
+                SERVICE_PATH,
+                SERVICE_INTERFACE,
+                "OpenSession",
+                &("plain", Value::from("")),
+            )
+            .await?;
+            debug!("Opened Secret Service session {}", path.as_str());
+            session.path = path;
+        }
+        Ok(session)
+    }
+
+    /// Look up the secret at `loc`.
+    async fn get_loc(
+        &mut self,
+        searched_for: &str,
+        loc: Option<&Location>,
+    ) -> Result<String> {
+        let loc = loc.ok_or_else(|| Error::MissingEntry {
+            name: searched_for.to_owned(),
+        })?;
+        let attributes = attributes(loc);
+        let session = self.session().await?;
+
+        let (unlocked, locked): (Vec<OwnedObjectPath>, Vec<OwnedObjectPath>) = call(
+            session,
+            SERVICE_PATH,
+            SERVICE_INTERFACE,
+            "SearchItems",
+            &(&attributes,),
+        )
+        .await?;
+        let item = match unlocked.into_iter().next() {
+            Some(item) => item,
+            None if !locked.is_empty() => {
+                return Err(Error::Other(
+                    format!("Secret Service item for {} is locked", loc).into(),
+                ))
+            }
+            None => {
+                return Err(Error::Other(
+                    format!("no Secret Service item for {}", loc).into(),
+                ))
+            }
+        };
+
+        let mut secrets: HashMap<OwnedObjectPath, RawSecret> = call(
+            session,
+            SERVICE_PATH,
+            SERVICE_INTERFACE,
+            "GetSecrets",
+            &(vec![&item], &session.path),
+        )
+        .await?;
+        let (_, _, value, _) = secrets.remove(&item).ok_or_else(|| {
+            Error::Other(
+                format!("could not get Secret Service item for {}", loc).into(),
+            )
+        })?;
+        debug!("Found credential in Secret Service item {}", item.as_str());
+        Ok(String::from_utf8(value)?)
+    }
+
+    /// Store `value` at `loc`, replacing any existing item with the same
+    /// attributes.
+    async fn set_loc(
+        &mut self,
+        searched_for: &str,
+        loc: Option<&Location>,
+        value: &str,
+    ) -> Result<()> {
+        let loc = loc.ok_or_else(|| Error::MissingEntry {
+            name: searched_for.to_owned(),
+        })?;
+        let attributes = attributes(loc);
+        let session = self.session().await?;
+
+        let mut properties: HashMap<&str, Value<'_>> = HashMap::new();
+        properties.insert(
+            "org.freedesktop.Secret.Item.Label",
+            Value::from(searched_for),
+        );
+        properties.insert(
+            "org.freedesktop.Secret.Item.Attributes",
+            Value::from(attributes),
+        );
+        let secret = (
+            &session.path,
+            Vec::<u8>::new(),
+            value.as_bytes(),
+            "text/plain; charset=utf8",
+        );
+        let (item, prompt): (OwnedObjectPath, OwnedObjectPath) = call(
+            session,
+            DEFAULT_COLLECTION_PATH,
+            COLLECTION_INTERFACE,
+            "CreateItem",
+            &(properties, secret, true),
+        )
+        .await?;
+        if prompt.as_str() != "/" {
+            return Err(Error::Other(
+                "the default Secret Service collection is locked".into(),
+            ));
+        }
+        debug!("Stored credential in Secret Service item {}", item.as_str());
+        Ok(())
+    }
+
+    /// Store `value` as the credential `credential`, at the location
+    /// specified in `secretfile`.
+    pub async fn set_var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+        value: &str,
+    ) -> Result<()> {
+        self.set_loc(credential, secretfile.var(credential), value)
+            .await
+    }
+
+    /// Store `value` as the contents of the file `path`, at the location
+    /// specified in `secretfile`.
+    pub async fn set_file(
+        &mut self,
+        secretfile: &Secretfile,
+        path: &str,
+        value: &str,
+    ) -> Result<()> {
+        self.set_loc(path, secretfile.file(path), value).await
+    }
+}
+
+/// The attributes we use to identify the item for `loc`.
+fn attributes(loc: &Location) -> HashMap<&str, &str> {
+    let mut attributes = HashMap::new();
+    attributes.insert("application", APPLICATION);
+    match *loc {
+        Location::Path(ref path) => {
+            attributes.insert("path", path.as_str());
+            attributes.insert("key", "");
+        }
+        Location::PathWithKey(ref path, ref key) => {
+            attributes.insert("path", path.as_str());
+            attributes.insert("key", key.as_str());
+        }
+    }
+    attributes
+}
+
+/// Call a method of the Secret Service and return the reply.
+async fn call<B, R>(
+    session: &Session,
+    path: &str,
+    interface: &str,
+    method: &str,
+    body: &B,
+) -> Result<R>
+where
+    B: serde::Serialize + zbus::zvariant::DynamicType,
+    R: for<'d> zbus::zvariant::DynamicDeserialize<'d>,
+{
+    let reply = session
+        .connection
+        .call_method(Some(SERVICE_NAME), path, Some(interface), method, body)
+        .await?;
+    Ok(reply.body().deserialize()?)
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "secret-service"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        self.get_loc(credential, secretfile.var(credential)).await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        self.get_loc(path, secretfile.file(path)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::UnixStream;
+    use zbus::connection::Builder;
+    use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+    use zbus::{interface, Guid};
+
+    use super::{Client, RawSecret};
+    use crate::backend::Backend;
+    use crate::secretfile::Secretfile;
+
+    /// The items stored by our stand-in service.
+    type Items = Arc<Mutex<Vec<(HashMap<String, String>, Vec<u8>)>>>;
+
+    fn item_path(index: usize) -> OwnedObjectPath {
+        OwnedObjectPath::try_from(format!(
+            "/org/freedesktop/secrets/collection/login/{}",
+            index
+        ))
+        .unwrap()
+    }
+
+    /// A minimal stand-in for the Secret Service.
+    struct FakeService {
+        items: Items,
+    }
+
+    #[interface(name = "org.freedesktop.Secret.Service")]
+    impl FakeService {
+        fn open_session(
+            &self,
+            algorithm: &str,
+            _input: Value<'_>,
+        ) -> zbus::fdo::Result<(OwnedValue, OwnedObjectPath)> {
+            if algorithm != "plain" {
+                return Err(zbus::fdo::Error::NotSupported(algorithm.to_owned()));
+            }
+            let path = OwnedObjectPath::try_from("/org/freedesktop/secrets/session/1")
+                .unwrap();
+            Ok((OwnedValue::from(0u32), path))
+        }
+
+        fn search_items(
+            &self,
+            attributes: HashMap<String, String>,
+        ) -> (Vec<OwnedObjectPath>, Vec<OwnedObjectPath>) {
+            let items = self.items.lock().unwrap();
+            let found = items
+                .iter()
+                .enumerate()
+                .filter(|(_, (attrs, _))| {
+                    attributes.iter().all(|(k, v)| attrs.get(k) == Some(v))
+                })
+                .map(|(i, _)| item_path(i))
+                .collect();
+            (found, vec![])
+        }
+
+        fn get_secrets(
+            &self,
+            requested: Vec<OwnedObjectPath>,
+            session: OwnedObjectPath,
+        ) -> HashMap<OwnedObjectPath, RawSecret> {
+            let items = self.items.lock().unwrap();
+            (0..items.len())
+                .filter(|&i| requested.contains(&item_path(i)))
+                .map(|i| {
+                    let secret = (
+                        session.clone(),
+                        vec![],
+                        items[i].1.clone(),
+                        "text/plain".to_owned(),
+                    );
+                    (item_path(i), secret)
+                })
+                .collect()
+        }
+    }
+
+    /// A stand-in for the default collection.
+    struct FakeCollection {
+        items: Items,
+    }
+
+    #[interface(name = "org.freedesktop.Secret.Collection")]
+    impl FakeCollection {
+        fn create_item(
+            &self,
+            properties: HashMap<String, OwnedValue>,
+            secret: RawSecret,
+            replace: bool,
+        ) -> zbus::fdo::Result<(OwnedObjectPath, OwnedObjectPath)> {
+            let attributes = properties
+                .get("org.freedesktop.Secret.Item.Attributes")
+                .and_then(|v| {
+                    HashMap::<String, String>::try_from(v.try_clone().ok()?).ok()
+                })
+                .ok_or_else(|| {
+                    zbus::fdo::Error::InvalidArgs("attributes".to_owned())
+                })?;
+            let mut items = self.items.lock().unwrap();
+            let existing = items.iter().position(|(attrs, _)| *attrs == attributes);
+            let index = match existing {
+                Some(i) if replace => {
+                    items[i].1 = secret.2;
+                    i
+                }
+                _ => {
+                    items.push((attributes, secret.2));
+                    items.len() - 1
+                }
+            };
+            Ok((item_path(index), OwnedObjectPath::try_from("/").unwrap()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_secret_service() {
+        // An item belonging to another application, which we should ignore.
+        let other: HashMap<String, String> =
+            vec![("path".to_owned(), "other".to_owned())]
+                .into_iter()
+                .collect();
+        let items = Items::new(Mutex::new(vec![(other, b"other".to_vec())]));
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let server = Builder::unix_stream(server_stream)
+            .server(Guid::generate())
+            .unwrap()
+            .p2p()
+            .serve_at(
+                "/org/freedesktop/secrets",
+                FakeService {
+                    items: items.clone(),
+                },
+            )
+            .unwrap()
+            .serve_at(
+                "/org/freedesktop/secrets/aliases/default",
+                FakeCollection {
+                    items: items.clone(),
+                },
+            )
+            .unwrap()
+            .build();
+        let client = Builder::unix_stream(client_stream).p2p().build();
+        let (_server, connection) = tokio::try_join!(server, client).unwrap();
+
+        let sf = Secretfile::from_str(
+            "\
+GITHUB_TOKEN github:token
+GITHUB_USER github:user
+GITHUB github
+DB_PASSWORD db
+OTHER other
+>api.txt github:token
+",
+        )
+        .unwrap();
+        let mut client = Client::from_connection(connection);
+        assert!(client.var(&sf, "GITHUB_TOKEN").await.is_err());
+        client.set_var(&sf, "GITHUB_TOKEN", "abc").await.unwrap();
+        client.set_var(&sf, "GITHUB_USER", "octocat").await.unwrap();
+        client.set_var(&sf, "DB_PASSWORD", "secret").await.unwrap();
+        assert_eq!("abc", client.var(&sf, "GITHUB_TOKEN").await.unwrap());
+        assert_eq!("octocat", client.var(&sf, "GITHUB_USER").await.unwrap());
+        assert_eq!("secret", client.var(&sf, "DB_PASSWORD").await.unwrap());
+        assert_eq!("abc", client.file(&sf, "api.txt").await.unwrap());
+
+        client.set_var(&sf, "GITHUB_TOKEN", "xyz").await.unwrap();
+        assert_eq!("xyz", client.var(&sf, "GITHUB_TOKEN").await.unwrap());
+        assert_eq!(4, items.lock().unwrap().len());
+        for (attrs, _) in &items.lock().unwrap()[1..] {
+            assert_eq!("credentials", attrs["application"]);
+        }
+
+        // A whole-path entry doesn't match items stored for a key.
+        assert!(client.var(&sf, "GITHUB").await.is_err());
+        assert_eq!("", items.lock().unwrap()[3].0["key"]);
+        assert!(client.var(&sf, "OTHER").await.is_err());
+        assert!(client.var(&sf, "NOSUCHVAR").await.is_err());
+    }
+}
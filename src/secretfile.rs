@@ -7,13 +7,15 @@
 
 use lazy_static::lazy_static;
 use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::collections::{btree_map, BTreeMap};
 use std::env;
+use std::fmt;
 use std::fs::File;
 use std::io::{self, BufRead};
 use std::iter::Iterator;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Mutex;
 
@@ -70,12 +72,13 @@ fn interpolate_env(text: &str) -> Result<String> {
     }
 }
 
-/// The location of a secret in a given backend.  This is exported to the
-/// rest of this crate, but isn't part of the public `Secretfile` API,
-/// because we might add more types of locations in the future.
+/// The location of a secret in a given backend.  We might add more types
+/// of locations in the future, so code outside this crate should normally
+/// use `path` and `key` instead of matching on the variants directly.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Location {
-    // Used for systems which identify credentials with simple string keys.
+    /// Used for systems which identify credentials with simple string keys.
     Path(String),
     /// Used for systems like Vault where a path _and_ a hash key are
     /// needed to identify a specific credential.
@@ -100,14 +103,378 @@ impl Location {
             }
         }
     }
+
+    /// The path of the secret, with any environment variables already
+    /// interpolated.
+    pub fn path(&self) -> &str {
+        match *self {
+            Location::Path(ref path) | Location::PathWithKey(ref path, _) => path,
+        }
+    }
+
+    /// The key within the secret, if this location has one.
+    pub fn key(&self) -> Option<&str> {
+        match *self {
+            Location::Path(_) => None,
+            Location::PathWithKey(_, ref key) => Some(key),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Location::Path(ref path) => write!(f, "{}", path),
+            Location::PathWithKey(ref path, ref key) => write!(f, "{}:{}", path, key),
+        }
+    }
+}
+
+/// Parse a `Location` written as `path` or `path:key`, optionally followed
+/// by transforms like `|base64`.
+fn parse_location(text: &str) -> Result<(Location, Vec<Transform>)> {
+    // Only compile this Regex once.
+    lazy_static! {
+        static ref RE: Regex = Regex::new(
+            r"^(?P<path>[^\s|]+?)(?::(?P<key>[^\s|]+))?(?P<transforms>(?:\|[^\s|]+)*)$"
+        )
+        .unwrap();
+    }
+    let caps = RE.captures(text).ok_or_else(|| Error::Parse {
+        input: text.to_owned(),
+    })?;
+    Ok((Location::from_caps(&caps)?, Transform::from_caps(&caps)?))
+}
+
+/// A transformation applied to a secret's value after it has been fetched
+/// from the backend.  In a `Secretfile`, these are appended to the
+/// location, as in `secret/ssl:keystore|base64`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Transform {
+    /// Decode base64 data.  Written as `|base64`.
+    Base64,
+    /// Remove leading and trailing whitespace.  Written as `|trim`.
+    Trim,
+    /// Parse the value as a JSON object and extract the specified field.
+    /// String fields are returned as-is, and any other values are returned
+    /// as JSON.  Written as `|json:field`.
+    Json(String),
+}
+
+impl Transform {
+    /// Parse the transforms in the named match `transforms`, if present.
+    fn from_caps(caps: &Captures<'_>) -> Result<Vec<Transform>> {
+        match caps.name("transforms") {
+            None => Ok(vec![]),
+            Some(m) => m.as_str().split('|').skip(1).map(|t| t.parse()).collect(),
+        }
+    }
+
+    /// Apply this transform to `value`.
+    fn apply(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+        match *self {
+            Transform::Base64 => Ok(base64::decode(trim_bytes(&value))?),
+            Transform::Trim => Ok(trim_bytes(&value).to_vec()),
+            Transform::Json(ref field) => {
+                let json: serde_json::Value = serde_json::from_slice(&value)?;
+                match json.get(field) {
+                    Some(serde_json::Value::String(s)) => Ok(s.clone().into_bytes()),
+                    Some(other) => Ok(serde_json::to_vec(other)?),
+                    None => Err(Error::MissingJsonField {
+                        field: field.to_owned(),
+                    }),
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Transform {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Transform> {
+        match s {
+            "base64" => Ok(Transform::Base64),
+            "trim" => Ok(Transform::Trim),
+            _ => match s.strip_prefix("json:") {
+                Some(field) if !field.is_empty() => {
+                    Ok(Transform::Json(field.to_owned()))
+                }
+                _ => Err(Error::Parse {
+                    input: s.to_owned(),
+                }),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Transform::Base64 => write!(f, "base64"),
+            Transform::Trim => write!(f, "trim"),
+            Transform::Json(ref field) => write!(f, "json:{}", field),
+        }
+    }
+}
+
+/// Remove leading and trailing ASCII whitespace from `bytes`.
+fn trim_bytes(bytes: &[u8]) -> &[u8] {
+    let start = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    let end = bytes
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(start, |i| i + 1);
+    &bytes[start..end]
+}
+
+/// Per-entry options.  Apart from `transforms`, these can only be specified
+/// in a structured `Secretfile.toml` (or `Secretfile.yaml`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryOptions {
+    transforms: Vec<Transform>,
+    optional: bool,
+    backend: Option<String>,
+    mode: Option<u32>,
+}
+
+impl EntryOptions {
+    /// The transforms applied to the value after it has been fetched, such
+    /// as decoding base64.
+    pub fn transforms(&self) -> &[Transform] {
+        &self.transforms
+    }
+
+    /// Has this entry been marked as optional?  This isn't interpreted by
+    /// `credentials` itself, but it allows tools and applications to
+    /// distinguish between required and optional credentials.
+    pub fn optional(&self) -> bool {
+        self.optional
+    }
+
+    /// The name of the only backend which should be used to look up this
+    /// entry, such as `"env"` or `"vault"`.
+    pub fn backend(&self) -> Option<&str> {
+        self.backend.as_deref()
+    }
+
+    /// The Unix permissions that a file-style credential should be written
+    /// with, such as `0o600`.  This is for use by applications which write
+    /// credentials to disk.
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    /// Combine `transforms` with the options from a structured `Secretfile`.
+    fn new(transforms: Vec<Transform>, structured: StructuredOptions) -> EntryOptions {
+        EntryOptions {
+            transforms,
+            optional: structured.optional,
+            backend: structured.backend,
+            mode: structured.mode,
+        }
+    }
+
+    /// Apply our transforms to `value`.
+    pub(crate) fn apply(&self, value: String) -> Result<Vec<u8>> {
+        self.transforms
+            .iter()
+            .try_fold(value.into_bytes(), |value, transform| {
+                transform.apply(value)
+            })
+    }
+}
+
+/// The per-entry options which can only be written in a structured
+/// `Secretfile`.  We keep these alongside each `Line`, so that they survive
+/// a trip through `SecretfileBuilder`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct StructuredOptions {
+    optional: bool,
+    backend: Option<String>,
+    mode: Option<u32>,
+}
+
+impl StructuredOptions {
+    /// Are these the same as the options for a plain `Secretfile` entry?
+    fn is_default(&self) -> bool {
+        *self == StructuredOptions::default()
+    }
+}
+
+/// A single `Secretfile` entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    location: Location,
+    options: EntryOptions,
+}
+
+impl Entry {
+    /// Create an entry with the default options, plus `transforms`.
+    fn with_transforms(location: Location, transforms: Vec<Transform>) -> Entry {
+        Entry {
+            location,
+            options: EntryOptions {
+                transforms,
+                ..EntryOptions::default()
+            },
+        }
+    }
+}
+
+/// The top level of a structured `Secretfile.toml` or `Secretfile.yaml`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct StructuredSecretfile {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    vars: BTreeMap<String, StructuredEntry>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    files: BTreeMap<String, StructuredEntry>,
+}
+
+/// An entry in a structured `Secretfile`, which may be written either as a
+/// `"path:key"` string or as a table with extra options.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum StructuredEntry {
+    Short(String),
+    Full(FullStructuredEntry),
+}
+
+/// The table form of a `StructuredEntry`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct FullStructuredEntry {
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    transforms: Vec<String>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    optional: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+}
+
+/// Is `value` the default for its type?  Used to omit default options when
+/// writing a structured `Secretfile`.
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+impl FullStructuredEntry {
+    /// Build a table entry from the text of a location and its options.
+    fn new(location: &str, options: &StructuredOptions) -> FullStructuredEntry {
+        let mut parts = location.split('|');
+        let head = parts.next().unwrap_or_default();
+        let (path, key) = match head.split_once(':') {
+            Some((path, key)) => (path.to_owned(), Some(key.to_owned())),
+            None => (head.to_owned(), None),
+        };
+        FullStructuredEntry {
+            path,
+            key,
+            transforms: parts.map(|t| t.to_owned()).collect(),
+            optional: options.optional,
+            backend: options.backend.clone(),
+            mode: options.mode,
+        }
+    }
+}
+
+impl StructuredEntry {
+    /// Convert into an `Entry`, plus the uninterpolated location text and
+    /// options used by `Display`.
+    fn into_entry(self) -> Result<(Entry, String, StructuredOptions)> {
+        match self {
+            StructuredEntry::Short(text) => {
+                let (location, transforms) = parse_location(&text)?;
+                let entry = Entry::with_transforms(location, transforms);
+                Ok((entry, text, StructuredOptions::default()))
+            }
+            StructuredEntry::Full(full) => {
+                let mut text = match full.key {
+                    Some(key) => format!("{}:{}", full.path, key),
+                    None => full.path,
+                };
+                for transform in &full.transforms {
+                    text.push('|');
+                    text.push_str(transform);
+                }
+                let (location, transforms) = parse_location(&text)?;
+                let options = StructuredOptions {
+                    optional: full.optional,
+                    backend: full.backend,
+                    mode: full.mode,
+                };
+                let entry = Entry {
+                    location,
+                    options: EntryOptions::new(transforms, options.clone()),
+                };
+                Ok((entry, text, options))
+            }
+        }
+    }
+}
+
+/// A single line of a `Secretfile`, as originally written.  We keep these
+/// around so that we can write a `Secretfile` back out without losing
+/// comments or reordering entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line {
+    /// A blank line or a comment, stored verbatim.
+    Comment(String),
+    /// A `VAR path:key` entry, before environment variable interpolation.
+    Var {
+        name: String,
+        location: String,
+        options: StructuredOptions,
+    },
+    /// A `>file path:key` entry, before environment variable interpolation.
+    File {
+        path: String,
+        location: String,
+        options: StructuredOptions,
+    },
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Line::Comment(ref text) => write!(f, "{}", text),
+            Line::Var {
+                ref name,
+                ref location,
+                ..
+            } => write!(f, "{} {}", name, location),
+            Line::File {
+                ref path,
+                ref location,
+                ..
+            } => write!(f, ">{} {}", path, location),
+        }
+    }
 }
 
 /// A basic interface for loading a `Secretfile` and listing the various
 /// variables and files contained inside.
+///
+/// A `Secretfile` can be written back out using `Display`, which preserves
+/// comments, ordering and uninterpolated `$VAR` references.  If any entry
+/// has options which can't be expressed in a plain `Secretfile`, such as a
+/// `mode`, `Display` returns an error, and you should use `to_toml`
+/// instead.
 #[derive(Debug, Clone)]
 pub struct Secretfile {
-    varmap: BTreeMap<String, Location>,
-    filemap: BTreeMap<String, Location>,
+    varmap: BTreeMap<String, Entry>,
+    filemap: BTreeMap<String, Entry>,
+    lines: Vec<Line>,
 }
 
 impl Secretfile {
@@ -128,8 +495,11 @@ impl Secretfile {
      >(?P<file>\S+)
    )
    \s+
-   # path/to/secret:key
-   (?P<path>\S+?)(?::(?P<key>\S+))?
+   # path/to/secret:key|transform
+   (?P<location>
+     (?P<path>[^\s|]+?)(?::(?P<key>[^\s|]+))?
+     (?P<transforms>(?:\|[^\s|]+)*)
+   )
    \s*
  )$").unwrap();
         }
@@ -137,24 +507,40 @@ impl Secretfile {
         let mut sf = Secretfile {
             varmap: BTreeMap::new(),
             filemap: BTreeMap::new(),
+            lines: vec![],
         };
         let buffer = io::BufReader::new(read);
         for line_or_err in buffer.lines() {
             let line = line_or_err?;
             match RE.captures(&line) {
                 Some(ref caps) if caps.name("path").is_some() => {
-                    let location = Location::from_caps(caps)?;
+                    let entry = Entry::with_transforms(
+                        Location::from_caps(caps)?,
+                        Transform::from_caps(caps)?,
+                    );
+                    let raw_location = caps.name("location").unwrap().as_str();
                     if caps.name("file").is_some() {
-                        let file =
-                            interpolate_env(caps.name("file").unwrap().as_str())?;
-                        sf.filemap.insert(file, location);
+                        let raw_file = caps.name("file").unwrap().as_str();
+                        let file = interpolate_env(raw_file)?;
+                        sf.filemap.insert(file, entry);
+                        sf.lines.push(Line::File {
+                            path: raw_file.to_owned(),
+                            location: raw_location.to_owned(),
+                            options: StructuredOptions::default(),
+                        });
                     } else if caps.name("var").is_some() {
                         let var = caps.name("var").unwrap().as_str().to_owned();
-                        sf.varmap.insert(var, location);
+                        sf.varmap.insert(var.clone(), entry);
+                        sf.lines.push(Line::Var {
+                            name: var,
+                            location: raw_location.to_owned(),
+                            options: StructuredOptions::default(),
+                        });
                     }
                 }
                 Some(_) => {
-                    // Blank or comment
+                    // Blank or comment.  Keep it so we can write it back out.
+                    sf.lines.push(Line::Comment(line.clone()));
                 }
                 _ => {
                     return Err(Error::Parse {
@@ -166,22 +552,105 @@ impl Secretfile {
         Ok(sf)
     }
 
+    fn from_structured(structured: StructuredSecretfile) -> Result<Secretfile> {
+        // Only compile this Regex once.
+        lazy_static! {
+            static ref VAR_RE: Regex =
+                Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap();
+        }
+
+        let mut sf = Secretfile {
+            varmap: BTreeMap::new(),
+            filemap: BTreeMap::new(),
+            lines: vec![],
+        };
+        for (var, structured_entry) in structured.vars {
+            if !VAR_RE.is_match(&var) {
+                return Err(Error::Parse { input: var });
+            }
+            let (entry, location, options) = structured_entry.into_entry()?;
+            sf.varmap.insert(var.clone(), entry);
+            sf.lines.push(Line::Var {
+                name: var,
+                location,
+                options,
+            });
+        }
+        for (raw_file, structured_entry) in structured.files {
+            let (entry, location, options) = structured_entry.into_entry()?;
+            sf.filemap.insert(interpolate_env(&raw_file)?, entry);
+            sf.lines.push(Line::File {
+                path: raw_file,
+                location,
+                options,
+            });
+        }
+        Ok(sf)
+    }
+
     /// Read in from an `io::Read` object.
     pub fn read(read: &mut dyn io::Read) -> Result<Secretfile> {
         Secretfile::read_internal(read).map_err(|err| Error::Secretfile(Box::new(err)))
     }
 
-    /// Load the `Secretfile` at the specified path.
+    /// Parse a structured `Secretfile.toml`.  Each entry may be either a
+    /// `"path:key"` string, or a table with `path`, an optional `key`, and
+    /// any of the options described by `EntryOptions`:
+    ///
+    /// ```toml
+    /// [vars]
+    /// PG_USERNAME = "postgresql/$VAULT_ENV/creds/readonly:username"
+    /// PG_PASSWORD = { path = "postgresql/$VAULT_ENV/creds/readonly", key = "password", backend = "vault" }
+    ///
+    /// [files."ssl/key.pem"]
+    /// path = "secret/ssl"
+    /// key = "key_pem"
+    /// transforms = ["base64"]
+    /// mode = 0o600
+    /// ```
+    pub fn from_toml(text: &str) -> Result<Secretfile> {
+        toml::from_str(text)
+            .map_err(|err| Error::Toml(Box::new(err)))
+            .and_then(Secretfile::from_structured)
+            .map_err(|err| Error::Secretfile(Box::new(err)))
+    }
+
+    /// Parse a structured `Secretfile.yaml`, which has the same layout as a
+    /// `Secretfile.toml`.
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(text: &str) -> Result<Secretfile> {
+        serde_yaml::from_str(text)
+            .map_err(|err| Error::Yaml(Box::new(err)))
+            .and_then(Secretfile::from_structured)
+            .map_err(|err| Error::Secretfile(Box::new(err)))
+    }
+
+    /// Load the `Secretfile` at the specified path.  Files ending in
+    /// `.toml` (or `.yaml` and `.yml`, with the `yaml` feature) are parsed
+    /// as structured `Secretfile`s.
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Secretfile> {
         let path = path.as_ref();
-        let mut file = File::open(path).map_err(|err| Error::FileRead {
-            path: path.to_owned(),
-            source: Box::new(err.into()),
-        })?;
-        Secretfile::read(&mut file).map_err(|err| Error::FileRead {
+        let mkerr = |err| Error::FileRead {
             path: path.to_owned(),
             source: Box::new(err),
-        })
+        };
+        let mut file = File::open(path).map_err(|err| mkerr(err.into()))?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => {
+                let mut text = String::new();
+                io::Read::read_to_string(&mut file, &mut text)
+                    .map_err(|err| mkerr(err.into()))?;
+                Secretfile::from_toml(&text).map_err(mkerr)
+            }
+            #[cfg(feature = "yaml")]
+            Some("yaml") | Some("yml") => {
+                let mut text = String::new();
+                io::Read::read_to_string(&mut file, &mut text)
+                    .map_err(|err| mkerr(err.into()))?;
+                Secretfile::from_yaml(&text).map_err(mkerr)
+            }
+            _ => Secretfile::read(&mut file).map_err(mkerr),
+        }
     }
 
     /// Set a built-in `Secretfile`. This is intended for command-line
@@ -197,9 +666,37 @@ impl Secretfile {
         *guard.borrow_mut() = secretfile;
     }
 
-    /// Load the default `Secretfile`. This is normally `Secretfile` in the
-    /// current working directory, but it can be overridden using
-    /// `Secretfile::set_built_in`.
+    /// Start building a new `Secretfile` from scratch.
+    pub fn builder() -> SecretfileBuilder {
+        SecretfileBuilder::default()
+    }
+
+    /// Create a `SecretfileBuilder` containing all the lines of this
+    /// `Secretfile`, so that it can be edited and then written back out.
+    pub fn to_builder(&self) -> SecretfileBuilder {
+        SecretfileBuilder {
+            lines: self.lines.clone(),
+        }
+    }
+
+    /// Load the default `Secretfile`.  Unless one has been specified using
+    /// `Secretfile::set_built_in`, we look for it in the following places,
+    /// in order:
+    ///
+    /// 1. The path in the `CREDENTIALS_SECRETFILE` environment variable.
+    ///    If this is set, we don't look anywhere else.
+    /// 2. The current working directory, and then each of its parents, up
+    ///    to and including the first directory containing `.git`, or the
+    ///    user's home directory.
+    /// 3. `$XDG_CONFIG_HOME/credentials`, or `~/.config/credentials` if
+    ///    `XDG_CONFIG_HOME` is not set.
+    ///
+    /// In each directory, we look for `Secretfile`, followed by a
+    /// structured `Secretfile.toml` (or `Secretfile.yaml` and
+    /// `Secretfile.yml`, with the `yaml` feature).
+    // This predates our other constructors, and it can't implement
+    // `Default` because it can fail.
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> Result<Secretfile> {
         // We have to use some extra temporary variables to keep the borrow
         // checker happy.
@@ -210,13 +707,38 @@ impl Secretfile {
         if let Some(built_in) = built_in_opt {
             Ok(built_in)
         } else {
-            let mut path = env::current_dir()
+            let override_path =
+                env::var_os("CREDENTIALS_SECRETFILE").map(PathBuf::from);
+            let dir = env::current_dir()
                 .map_err(|err| Error::Secretfile(Box::new(err.into())))?;
-            path.push("Secretfile");
+            let home = dirs::home_dir();
+            let path =
+                find_secretfile(override_path, &dir, home.as_deref(), config_dir())?;
             Secretfile::from_path(path)
         }
     }
 
+    /// Fetch the backend location for a variable listed in this file.
+    pub fn var(&self, name: &str) -> Option<&Location> {
+        self.varmap.get(name).map(|entry| &entry.location)
+    }
+
+    /// Fetch the backend location for a file listed in this file.
+    pub fn file(&self, name: &str) -> Option<&Location> {
+        self.filemap.get(name).map(|entry| &entry.location)
+    }
+
+    /// Return the `EntryOptions` for an environment variable listed in this
+    /// file.
+    pub fn var_options(&self, name: &str) -> Option<&EntryOptions> {
+        self.varmap.get(name).map(|entry| &entry.options)
+    }
+
+    /// Return the `EntryOptions` for a credential file listed in this file.
+    pub fn file_options(&self, name: &str) -> Option<&EntryOptions> {
+        self.filemap.get(name).map(|entry| &entry.options)
+    }
+
     /// Return an iterator over the environment variables listed in this
     /// file.
     pub fn vars(&self) -> SecretfileKeys<'_> {
@@ -231,6 +753,139 @@ impl Secretfile {
             keys: self.filemap.keys(),
         }
     }
+
+    /// Return an iterator over `(name, location)` pairs for the environment
+    /// variables listed in this file.
+    pub fn var_locations(&self) -> SecretfileLocations<'_> {
+        SecretfileLocations {
+            entries: self.varmap.iter(),
+        }
+    }
+
+    /// Return an iterator over `(path, location)` pairs for the credential
+    /// files listed in this file.
+    pub fn file_locations(&self) -> SecretfileLocations<'_> {
+        SecretfileLocations {
+            entries: self.filemap.iter(),
+        }
+    }
+}
+
+/// The file names we try when looking for a default `Secretfile`, in order.
+const SECRETFILE_NAMES: &[&str] = &[
+    "Secretfile",
+    "Secretfile.toml",
+    #[cfg(feature = "yaml")]
+    "Secretfile.yaml",
+    #[cfg(feature = "yaml")]
+    "Secretfile.yml",
+];
+
+/// Our per-user configuration directory, `$XDG_CONFIG_HOME/credentials` or
+/// `~/.config/credentials`.  We use this on all platforms, so that it
+/// matches our documentation.
+pub(crate) fn config_dir() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| dirs::home_dir().map(|home| home.join(".config")))?;
+    Some(base.join("credentials"))
+}
+
+/// Search for a `Secretfile` as described by `Secretfile::default`.
+fn find_secretfile(
+    override_path: Option<PathBuf>,
+    current_dir: &Path,
+    home_dir: Option<&Path>,
+    config_dir: Option<PathBuf>,
+) -> Result<PathBuf> {
+    let mut tried = vec![];
+
+    if let Some(path) = override_path {
+        if path.exists() {
+            return Ok(path);
+        }
+        tried.push(path);
+        return Err(Error::SecretfileNotFound { tried });
+    }
+
+    let mut dirs = vec![];
+    for dir in current_dir.ancestors() {
+        dirs.push(dir.to_owned());
+        if dir.join(".git").exists() || Some(dir) == home_dir {
+            break;
+        }
+    }
+    dirs.extend(config_dir);
+
+    for dir in dirs {
+        for name in SECRETFILE_NAMES {
+            let path = dir.join(name);
+            if path.exists() {
+                return Ok(path);
+            }
+            tried.push(path);
+        }
+    }
+    Err(Error::SecretfileNotFound { tried })
+}
+
+impl Secretfile {
+    /// Write this `Secretfile` in the `Secretfile.toml` format, including
+    /// any per-entry options.  Comments are not included.
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(&self.to_structured())
+            .map_err(|err| Error::Toml(Box::new(err)))
+    }
+
+    /// Convert to the structured form used by `Secretfile.toml`.
+    fn to_structured(&self) -> StructuredSecretfile {
+        let mut structured = StructuredSecretfile::default();
+        for line in &self.lines {
+            match *line {
+                Line::Comment(_) => {}
+                Line::Var {
+                    ref name,
+                    ref location,
+                    ref options,
+                } => {
+                    let entry = FullStructuredEntry::new(location, options);
+                    structured
+                        .vars
+                        .insert(name.clone(), StructuredEntry::Full(entry));
+                }
+                Line::File {
+                    ref path,
+                    ref location,
+                    ref options,
+                } => {
+                    let entry = FullStructuredEntry::new(location, options);
+                    structured
+                        .files
+                        .insert(path.clone(), StructuredEntry::Full(entry));
+                }
+            }
+        }
+        structured
+    }
+}
+
+impl fmt::Display for Secretfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let has_options = self.lines.iter().any(|line| match *line {
+            Line::Var { ref options, .. } | Line::File { ref options, .. } => {
+                !options.is_default()
+            }
+            Line::Comment(_) => false,
+        });
+        if has_options {
+            return Err(fmt::Error);
+        }
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
 }
 
 impl FromStr for Secretfile {
@@ -242,24 +897,169 @@ impl FromStr for Secretfile {
     }
 }
 
-/// Internal methods for looking up `Location`s in `Secretfile`.  These are
-/// hidden in a separate trait so that we can export them _within_ this
-/// crate, but not expose them to other crates.
-pub trait SecretfileLookup {
-    /// Fetch the backend path for a variable listed in a `Secretfile`.
-    fn var(&self, name: &str) -> Option<&Location>;
-
-    /// Fetch the backend path for a file listed in a `Secretfile`.
-    fn file(&self, name: &str) -> Option<&Location>;
+/// Constructs or edits a `Secretfile` programmatically.  Like `Options`,
+/// each method takes `self` by value and returns the updated builder.
+///
+/// ```
+/// # fn main() -> credentials::Result<()> {
+/// use credentials::Secretfile;
+/// # std::env::set_var("VAULT_ENV", "production");
+///
+/// let secretfile = Secretfile::builder()
+///     .comment("Database credentials.")
+///     .var("PG_USERNAME", "postgresql/$VAULT_ENV/creds/readonly:username")
+///     .var("PG_PASSWORD", "postgresql/$VAULT_ENV/creds/readonly:password")
+///     .blank_line()
+///     .file(">ssl/key.pem", "secret/ssl:key_pem")
+///     .build()?;
+/// println!("{}", secretfile);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SecretfileBuilder {
+    lines: Vec<Line>,
 }
 
-impl SecretfileLookup for Secretfile {
-    fn var(&self, name: &str) -> Option<&Location> {
-        self.varmap.get(name)
+impl SecretfileBuilder {
+    /// Append a comment.  Text containing newlines becomes multiple comment
+    /// lines.
+    pub fn comment<S: AsRef<str>>(mut self, text: S) -> SecretfileBuilder {
+        for line in text.as_ref().lines() {
+            let comment = if line.is_empty() {
+                "#".to_owned()
+            } else {
+                format!("# {}", line)
+            };
+            self.lines.push(Line::Comment(comment));
+        }
+        self
+    }
+
+    /// Append a blank line.
+    pub fn blank_line(mut self) -> SecretfileBuilder {
+        self.lines.push(Line::Comment(String::new()));
+        self
+    }
+
+    /// Add an environment-variable-style credential `name`, stored at
+    /// `location` (written as `path` or `path:key`, and possibly containing
+    /// `$VAR` references).  If `name` is already present, its entry is
+    /// replaced in place.
+    pub fn var<N, L>(mut self, name: N, location: L) -> SecretfileBuilder
+    where
+        N: Into<String>,
+        L: Into<String>,
+    {
+        let name = name.into();
+        let existing = self.lines.iter().position(|l| match *l {
+            Line::Var { name: ref n, .. } => *n == name,
+            _ => false,
+        });
+        let line = Line::Var {
+            name,
+            location: location.into(),
+            options: self.options_at(existing),
+        };
+        self.replace_or_push(existing, line);
+        self
+    }
+
+    /// Add a file-style credential at `path`, stored at `location`.  A
+    /// leading `>` on `path` is optional.  If `path` is already present,
+    /// its entry is replaced in place.
+    pub fn file<P, L>(mut self, path: P, location: L) -> SecretfileBuilder
+    where
+        P: Into<String>,
+        L: Into<String>,
+    {
+        let path = path.into();
+        let path = path.strip_prefix('>').unwrap_or(&path).to_owned();
+        let existing = self.lines.iter().position(|l| match *l {
+            Line::File { path: ref p, .. } => *p == path,
+            _ => false,
+        });
+        let line = Line::File {
+            path,
+            location: location.into(),
+            options: self.options_at(existing),
+        };
+        self.replace_or_push(existing, line);
+        self
+    }
+
+    /// Remove the entry for the environment-variable-style credential
+    /// `name`, if present.
+    pub fn remove_var<S: AsRef<str>>(mut self, name: S) -> SecretfileBuilder {
+        let name = name.as_ref();
+        self.lines.retain(|l| match *l {
+            Line::Var { name: ref n, .. } => n != name,
+            _ => true,
+        });
+        self
+    }
+
+    /// Remove the entry for the file-style credential at `path`, if
+    /// present.
+    pub fn remove_file<S: AsRef<str>>(mut self, path: S) -> SecretfileBuilder {
+        let path = path.as_ref();
+        let path = path.strip_prefix('>').unwrap_or(path);
+        self.lines.retain(|l| match *l {
+            Line::File { path: ref p, .. } => p != path,
+            _ => true,
+        });
+        self
     }
 
-    fn file(&self, name: &str) -> Option<&Location> {
-        self.filemap.get(name)
+    /// Build the `Secretfile`.  This validates each entry and interpolates
+    /// environment variables exactly as if the `Secretfile` had been read
+    /// from disk.  Any options from a structured `Secretfile` are kept.
+    pub fn build(self) -> Result<Secretfile> {
+        let mut text = String::new();
+        for line in &self.lines {
+            text.push_str(&format!("{}\n", line));
+        }
+        let mut sf: Secretfile = text.parse()?;
+        for line in &self.lines {
+            let (entry, options) = match *line {
+                Line::Var {
+                    ref name,
+                    ref options,
+                    ..
+                } => (sf.varmap.get_mut(name), options),
+                Line::File {
+                    ref path,
+                    ref options,
+                    ..
+                } => (sf.filemap.get_mut(&interpolate_env(path)?), options),
+                Line::Comment(_) => continue,
+            };
+            if let Some(entry) = entry {
+                let transforms = entry.options.transforms.clone();
+                entry.options = EntryOptions::new(transforms, options.clone());
+            }
+        }
+        sf.lines = self.lines;
+        Ok(sf)
+    }
+
+    /// The options of the entry at `index`, if any, so that we can keep
+    /// them when replacing its location.
+    fn options_at(&self, index: Option<usize>) -> StructuredOptions {
+        match index.map(|i| &self.lines[i]) {
+            Some(Line::Var { options, .. }) | Some(Line::File { options, .. }) => {
+                options.clone()
+            }
+            _ => StructuredOptions::default(),
+        }
+    }
+
+    /// Replace the line at `index`, or append `line` if there isn't one.
+    fn replace_or_push(&mut self, index: Option<usize>, line: Line) {
+        match index {
+            Some(i) => self.lines[i] = line,
+            None => self.lines.push(line),
+        }
     }
 }
 
@@ -268,7 +1068,7 @@ impl SecretfileLookup for Secretfile {
 pub struct SecretfileKeys<'a> {
     /// Our actual iterator, wrapped up only so that we don't need to
     /// expose the underlying implementation type in our stable API.
-    keys: btree_map::Keys<'a, String, Location>,
+    keys: btree_map::Keys<'a, String, Entry>,
 }
 
 // 'a is a lifetime specifier bound to the underlying collection we're
@@ -282,6 +1082,23 @@ impl<'a> Iterator for SecretfileKeys<'a> {
     }
 }
 
+/// An iterator over the names and locations mentioned in a `Secretfile`.
+#[derive(Clone)]
+pub struct SecretfileLocations<'a> {
+    /// Our actual iterator, wrapped up so that we don't expose `Entry`.
+    entries: btree_map::Iter<'a, String, Entry>,
+}
+
+impl<'a> Iterator for SecretfileLocations<'a> {
+    type Item = (&'a String, &'a Location);
+
+    fn next(&mut self) -> Option<(&'a String, &'a Location)> {
+        self.entries
+            .next()
+            .map(|(name, entry)| (name, &entry.location))
+    }
+}
+
 #[test]
 fn test_parse() {
     use std::str::FromStr;
@@ -327,3 +1144,303 @@ FOO_USERNAME2 ${SECRET_NAME}_username
         secretfile.files().collect::<Vec<_>>()
     );
 }
+
+#[test]
+fn test_display_round_trip() {
+    use std::str::FromStr;
+
+    let data = "\
+# Comments and blank lines are preserved.
+
+FOO_USERNAME secret/$ROUND_TRIP_NAME:username
+FOO_USERNAME2 ${ROUND_TRIP_NAME}_username
+>$ROUND_TRIP_DIR/key.pem secret/ssl:key_pem
+  # Indented comment.
+";
+    env::set_var("ROUND_TRIP_NAME", "foo");
+    env::set_var("ROUND_TRIP_DIR", "/home/foo");
+    let secretfile = Secretfile::from_str(data).unwrap();
+    assert_eq!(data, secretfile.to_string());
+}
+
+#[test]
+fn test_builder() {
+    env::set_var("BUILDER_ENV", "prod");
+    let secretfile = Secretfile::builder()
+        .comment("Generated.\nDo not edit.")
+        .var("PG_USERNAME", "postgresql/$BUILDER_ENV/creds:username")
+        .var("PG_PASSWORD", "postgresql/$BUILDER_ENV/creds:password")
+        .blank_line()
+        .file(">key.pem", "secret/ssl:key_pem")
+        .var("PG_USERNAME", "postgresql/$BUILDER_ENV/creds:user")
+        .build()
+        .unwrap();
+    assert_eq!(
+        "\
+# Generated.
+# Do not edit.
+PG_USERNAME postgresql/$BUILDER_ENV/creds:user
+PG_PASSWORD postgresql/$BUILDER_ENV/creds:password
+
+>key.pem secret/ssl:key_pem
+",
+        secretfile.to_string()
+    );
+    assert_eq!(
+        &Location::PathWithKey("postgresql/prod/creds".to_owned(), "user".to_owned()),
+        secretfile.var("PG_USERNAME").unwrap()
+    );
+
+    let edited = secretfile
+        .to_builder()
+        .remove_var("PG_PASSWORD")
+        .remove_file("key.pem")
+        .build()
+        .unwrap();
+    assert_eq!(vec!["PG_USERNAME"], edited.vars().collect::<Vec<_>>());
+    assert_eq!(0, edited.files().count());
+
+    assert!(Secretfile::builder()
+        .var("BAD NAME", "a:b")
+        .build()
+        .is_err());
+}
+
+#[test]
+fn test_parse_toml() {
+    let data = r#"
+[vars]
+FOO_USERNAME = "secret/$TOML_SECRET_NAME:username"
+FOO_PASSWORD = { path = "secret/${TOML_SECRET_NAME}", key = "password", backend = "vault", optional = true }
+FOO_USERNAME2 = { path = "${TOML_SECRET_NAME}_username" }
+
+[files."$TOML_SOMEDIR/.conf/key.pem"]
+path = "secret/ssl"
+key = "key_pem"
+transforms = ["base64"]
+mode = 0o600
+"#;
+    env::set_var("TOML_SECRET_NAME", "foo");
+    env::set_var("TOML_SOMEDIR", "/home/foo");
+    let secretfile = Secretfile::from_toml(data).unwrap();
+    assert_eq!(
+        &Location::PathWithKey("secret/foo".to_owned(), "username".to_owned()),
+        secretfile.var("FOO_USERNAME").unwrap()
+    );
+    assert_eq!(
+        &Location::PathWithKey("secret/foo".to_owned(), "password".to_owned()),
+        secretfile.var("FOO_PASSWORD").unwrap()
+    );
+    assert_eq!(
+        &Location::Path("foo_username".to_owned()),
+        secretfile.var("FOO_USERNAME2").unwrap()
+    );
+    assert_eq!(
+        &Location::PathWithKey("secret/ssl".to_owned(), "key_pem".to_owned()),
+        secretfile.file("/home/foo/.conf/key.pem").unwrap()
+    );
+
+    let password_options = secretfile.var_options("FOO_PASSWORD").unwrap();
+    assert_eq!(Some("vault"), password_options.backend());
+    assert!(password_options.optional());
+    assert_eq!(
+        &EntryOptions::default(),
+        secretfile.var_options("FOO_USERNAME").unwrap()
+    );
+    let key_options = secretfile.file_options("/home/foo/.conf/key.pem").unwrap();
+    assert_eq!(&[Transform::Base64], key_options.transforms());
+    assert_eq!(Some(0o600), key_options.mode());
+
+    assert!(Secretfile::from_toml("[vars]\n\"BAD NAME\" = \"a:b\"\n").is_err());
+    assert!(Secretfile::from_toml("[vars.FOO]\npath = \"a\"\nbogus = 1\n").is_err());
+}
+
+#[test]
+fn test_toml_round_trip() {
+    let data = r#"
+[vars]
+PLAIN = "secret/plain:value"
+PASSWORD = { path = "secret/db", key = "password", backend = "vault", optional = true }
+
+[files."ssl/key.pem"]
+path = "secret/ssl"
+key = "key_pem"
+transforms = ["base64", "trim"]
+mode = 0o600
+"#;
+    let original = Secretfile::from_toml(data).unwrap();
+    let check = |sf: &Secretfile| {
+        assert_eq!(original.var("PASSWORD"), sf.var("PASSWORD"));
+        assert_eq!(original.var_options("PASSWORD"), sf.var_options("PASSWORD"));
+        assert_eq!(original.var_options("PLAIN"), sf.var_options("PLAIN"));
+        assert_eq!(original.file("ssl/key.pem"), sf.file("ssl/key.pem"));
+        assert_eq!(
+            original.file_options("ssl/key.pem"),
+            sf.file_options("ssl/key.pem")
+        );
+    };
+
+    // Options survive a trip through the builder, even when an entry's
+    // location is replaced.
+    let rebuilt = original
+        .to_builder()
+        .file("ssl/key.pem", "secret/ssl:key_pem|base64|trim")
+        .build()
+        .unwrap();
+    check(&rebuilt);
+
+    // ...and through `to_toml`.
+    let text = rebuilt.to_toml().unwrap();
+    assert!(text.contains("[files.\"ssl/key.pem\"]"));
+    check(&Secretfile::from_toml(&text).unwrap());
+
+    // But `Display` can't write these options.
+    use std::fmt::Write;
+    assert!(write!(String::new(), "{}", rebuilt).is_err());
+}
+
+#[cfg(feature = "yaml")]
+#[test]
+fn test_parse_yaml() {
+    let data = "
+vars:
+  FOO_USERNAME: secret/foo:username
+  FOO_PASSWORD:
+    path: secret/foo
+    key: password
+    transforms: [base64]
+";
+    let secretfile = Secretfile::from_yaml(data).unwrap();
+    assert_eq!(
+        &Location::PathWithKey("secret/foo".to_owned(), "username".to_owned()),
+        secretfile.var("FOO_USERNAME").unwrap()
+    );
+    assert_eq!(
+        &[Transform::Base64],
+        secretfile.var_options("FOO_PASSWORD").unwrap().transforms()
+    );
+}
+
+#[test]
+fn test_locations() {
+    let data = "\
+FOO_USERNAME secret/foo:username
+FOO_TOKEN foo_token
+>key.pem secret/ssl:key_pem
+";
+    let secretfile = Secretfile::from_str(data).unwrap();
+    let vars = secretfile
+        .var_locations()
+        .map(|(name, loc)| (name.as_str(), loc.path(), loc.key()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            ("FOO_TOKEN", "foo_token", None),
+            ("FOO_USERNAME", "secret/foo", Some("username")),
+        ],
+        vars
+    );
+    let (file, loc) = secretfile.file_locations().next().unwrap();
+    assert_eq!("key.pem", file);
+    assert_eq!("secret/ssl:key_pem", loc.to_string());
+}
+
+#[test]
+fn test_find_secretfile() {
+    use std::fs;
+
+    let root = tempfile::tempdir().unwrap();
+    let repo = root.path().join("repo");
+    let subdir = repo.join("src/bin");
+    let config = root.path().join("config");
+    fs::create_dir_all(repo.join(".git")).unwrap();
+    fs::create_dir_all(&subdir).unwrap();
+    fs::create_dir_all(&config).unwrap();
+
+    // Nothing to find.  We stop at the repository root, then try the config
+    // directory.
+    let tried = match find_secretfile(None, &subdir, None, Some(config.clone())) {
+        Err(Error::SecretfileNotFound { tried }) => tried,
+        other => panic!("unexpected result: {:?}", other),
+    };
+    assert!(tried.contains(&subdir.join("Secretfile")));
+    assert!(tried.contains(&repo.join("Secretfile.toml")));
+    assert!(!tried.contains(&root.path().join("Secretfile")));
+    assert_eq!(
+        Some(&config.join(SECRETFILE_NAMES[SECRETFILE_NAMES.len() - 1])),
+        tried.last()
+    );
+
+    // The config directory is used as a last resort.
+    fs::write(config.join("Secretfile"), "").unwrap();
+    assert_eq!(
+        config.join("Secretfile"),
+        find_secretfile(None, &subdir, None, Some(config.clone())).unwrap()
+    );
+
+    // Files in parent directories take priority.
+    fs::write(repo.join("Secretfile.toml"), "").unwrap();
+    assert_eq!(
+        repo.join("Secretfile.toml"),
+        find_secretfile(None, &subdir, None, Some(config.clone())).unwrap()
+    );
+
+    // Without a repository, we stop at the home directory.
+    let home = root.path().join("home");
+    let project = home.join("project");
+    fs::create_dir_all(&project).unwrap();
+    let tried = match find_secretfile(None, &project, Some(&home), None) {
+        Err(Error::SecretfileNotFound { tried }) => tried,
+        other => panic!("unexpected result: {:?}", other),
+    };
+    assert!(tried.contains(&home.join("Secretfile")));
+    assert!(!tried.contains(&root.path().join("Secretfile")));
+
+    // The override is always used, even if it doesn't exist.
+    let custom = root.path().join("custom");
+    assert!(find_secretfile(Some(custom.clone()), &subdir, None, None).is_err());
+    fs::write(&custom, "").unwrap();
+    assert_eq!(
+        custom.clone(),
+        find_secretfile(Some(custom), &subdir, None, None).unwrap()
+    );
+}
+
+#[test]
+fn test_transforms() {
+    let data = "\
+TLS_KEY secret/tls:key|base64|trim
+DB_PASSWORD secret/db:json|json:password
+>keystore.jks secret/keystore:value|base64
+";
+    let secretfile = Secretfile::from_str(data).unwrap();
+    assert_eq!(data, secretfile.to_string());
+    assert_eq!(
+        &Location::PathWithKey("secret/tls".to_owned(), "key".to_owned()),
+        secretfile.var("TLS_KEY").unwrap()
+    );
+    let options = secretfile.var_options("TLS_KEY").unwrap();
+    assert_eq!(&[Transform::Base64, Transform::Trim], options.transforms());
+    assert_eq!(
+        b"secret".to_vec(),
+        options.apply("IHNlY3JldAo=\n".to_owned()).unwrap()
+    );
+
+    let options = secretfile.var_options("DB_PASSWORD").unwrap();
+    assert_eq!(
+        b"pw".to_vec(),
+        options.apply(r#"{"password":"pw"}"#.to_owned()).unwrap()
+    );
+    assert!(options.apply(r#"{"user":"u"}"#.to_owned()).is_err());
+
+    let options = secretfile.file_options("keystore.jks").unwrap();
+    assert_eq!(vec![0xff, 0x00], options.apply("/wA=".to_owned()).unwrap());
+
+    assert!(Secretfile::from_str("FOO secret/foo:bar|rot13\n").is_err());
+
+    let secretfile = Secretfile::from_toml(
+        "[vars.FOO]\npath = \"secret/foo\"\nkey = \"bar\"\ntransforms = [\"trim\"]\n",
+    )
+    .unwrap();
+    assert_eq!("FOO secret/foo:bar|trim\n", secretfile.to_string());
+}
@@ -0,0 +1,449 @@
+//! A backend which reads secrets from SOPS-encrypted YAML or JSON files,
+//! using age identities.
+//!
+//! Entries in the `Secretfile` specify the encrypted file, plus a dotted
+//! path to the value inside it:
+//!
+//! ```text
+//! DB_PASSWORD secrets/production.yaml:database.password
+//! ```
+//!
+//! We look for age identities in `SOPS_AGE_KEY` and `SOPS_AGE_KEY_FILE`,
+//! just like `sops` itself, falling back to `~/.config/sops/age/keys.txt`.
+
+use aes_gcm::aead::consts::U32;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::aes::Aes256;
+use aes_gcm::AesGcm;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_yaml::Value;
+use sha2::{Digest, Sha512};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+use crate::age::Identities;
+use crate::backend::Backend;
+use crate::errors::*;
+use crate::secretfile::{Location, Secretfile};
+
+/// The AES-GCM variant used by SOPS, which has 256-bit keys and 32-byte
+/// nonces.
+type SopsCipher = AesGcm<Aes256, U32>;
+
+/// Fetches credentials from SOPS-encrypted files.
+pub struct Client {
+    /// The age identities we use to decrypt data keys.
+    identities: Identities,
+    /// Local cache of decrypted files.
+    files: BTreeMap<String, Value>,
+}
+
+impl Client {
+    /// Has the user specified an age identity for use with SOPS?
+    pub fn is_enabled() -> bool {
+        env::var_os("SOPS_AGE_KEY").is_some()
+            || env::var_os("SOPS_AGE_KEY_FILE").is_some()
+    }
+
+    /// Construct a new `sops::Client`, looking for age identities in the
+    /// same places as `sops` itself.
+    pub fn from_env() -> Result<Client> {
+        let identities = if let Ok(key) = env::var("SOPS_AGE_KEY") {
+            Identities::parse(&key)?
+        } else {
+            let path = match env::var_os("SOPS_AGE_KEY_FILE") {
+                Some(path) => PathBuf::from(path),
+                None => dirs::config_dir()
+                    .ok_or(Error::NoHomeDirectory)?
+                    .join("sops/age/keys.txt"),
+            };
+            Identities::from_path(&path)?
+        };
+        Ok(Client::new(identities))
+    }
+
+    /// Construct a new `sops::Client` using the age identities in the
+    /// specified file.
+    pub fn with_identity_file<P: AsRef<Path>>(path: P) -> Result<Client> {
+        Ok(Client::new(Identities::from_path(path.as_ref())?))
+    }
+
+    fn new(identities: Identities) -> Client {
+        Client {
+            identities,
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Decrypt the SOPS file at `path`.
+    fn decrypt_file(&self, path: &str) -> Result<Value> {
+        let mkerr = |err| Error::FileRead {
+            path: PathBuf::from(path),
+            source: Box::new(err),
+        };
+        let text = fs::read_to_string(path).map_err(|err| mkerr(err.into()))?;
+        // JSON is valid YAML, and `serde_yaml` preserves the order of keys,
+        // which we need to check the MAC.
+        let doc: Value = serde_yaml::from_str(&text)
+            .map_err(|err| mkerr(Error::Yaml(Box::new(err))))?;
+        let value = self.decrypt_doc(doc).map_err(mkerr)?;
+        debug!("Decrypted SOPS file {}", path);
+        Ok(value)
+    }
+
+    /// Decrypt a parsed SOPS document, and verify its MAC.
+    fn decrypt_doc(&self, doc: Value) -> Result<Value> {
+        let mut tree = match doc {
+            Value::Mapping(tree) => tree,
+            _ => return Err(Error::Decrypt("SOPS file is not a mapping".into())),
+        };
+        let metadata = tree
+            .remove("sops")
+            .ok_or_else(|| Error::Decrypt("missing SOPS metadata".into()))?;
+        let key = self.data_key(&metadata)?;
+
+        let mut hasher = Sha512::new();
+        let mut path = vec![];
+        let mut tree = Value::Mapping(tree);
+        decrypt_tree(&mut tree, &key, &mut path, &mut hasher)?;
+
+        let mac = metadata_str(&metadata, "mac")?;
+        let last_modified = metadata_str(&metadata, "lastmodified")?;
+        let expected = decrypt_value(mac, &key, last_modified)?;
+        let actual = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>();
+        if expected != actual.as_bytes() {
+            return Err(Error::Decrypt("SOPS file has an invalid MAC".into()));
+        }
+        Ok(tree)
+    }
+
+    /// Decrypt the data key using one of the age recipients listed in our
+    /// metadata.
+    fn data_key(&self, metadata: &Value) -> Result<Vec<u8>> {
+        let recipients = metadata
+            .get("age")
+            .and_then(|age| age.as_sequence())
+            .ok_or_else(|| Error::Decrypt("SOPS file has no age recipients".into()))?;
+        let mut err = None;
+        for recipient in recipients {
+            let enc = match recipient.get("enc").and_then(|enc| enc.as_str()) {
+                Some(enc) => enc,
+                None => continue,
+            };
+            match self.identities.decrypt(enc.as_bytes()) {
+                Ok(key) => return Ok(key),
+                Err(e) => err = Some(e),
+            }
+        }
+        Err(err.unwrap_or_else(|| {
+            Error::Decrypt("SOPS file has no age recipients".into())
+        }))
+    }
+
+    async fn get_loc(
+        &mut self,
+        searched_for: &str,
+        loc: Option<Location>,
+    ) -> Result<String> {
+        match loc {
+            None => Err(Error::MissingEntry {
+                name: searched_for.to_owned(),
+            }),
+            Some(Location::PathWithKey(ref path, ref key)) => {
+                // Only decrypt each file once.
+                if !self.files.contains_key(path) {
+                    let value = self.decrypt_file(path)?;
+                    self.files.insert(path.to_owned(), value);
+                }
+                let missing = || Error::MissingKeyInSecret {
+                    secret: path.to_owned(),
+                    key: key.to_owned(),
+                };
+                let mut value = &self.files[path];
+                for segment in key.split('.') {
+                    value = match *value {
+                        Value::Sequence(ref seq) => segment
+                            .parse::<usize>()
+                            .ok()
+                            .and_then(|i| seq.get(i))
+                            .ok_or_else(missing)?,
+                        _ => value.get(segment).ok_or_else(missing)?,
+                    };
+                }
+                match *value {
+                    Value::String(ref s) => Ok(s.clone()),
+                    Value::Bool(b) => Ok(b.to_string()),
+                    Value::Number(ref n) => Ok(n.to_string()),
+                    Value::Null => Err(missing()),
+                    ref other => Ok(serde_json::to_string(other)?),
+                }
+            }
+            Some(Location::Path(ref path)) => Err(Error::MissingKeyInPath {
+                path: path.to_owned(),
+            }),
+        }
+    }
+}
+
+/// Look up a string in our SOPS metadata.
+fn metadata_str<'a>(metadata: &'a Value, key: &str) -> Result<&'a str> {
+    metadata.get(key).and_then(|v| v.as_str()).ok_or_else(|| {
+        Error::Decrypt(format!("missing SOPS metadata {:?}", key).into())
+    })
+}
+
+/// Recursively decrypt all the values in `value`, replacing them with
+/// strings, and hash the plaintext for our MAC.  `path` contains the keys
+/// leading to `value`, which SOPS uses as additional authenticated data.
+fn decrypt_tree(
+    value: &mut Value,
+    key: &[u8],
+    path: &mut Vec<String>,
+    hasher: &mut Sha512,
+) -> Result<()> {
+    match *value {
+        Value::Mapping(ref mut mapping) => {
+            for (k, v) in mapping.iter_mut() {
+                let k = match *k {
+                    Value::String(ref s) => s.clone(),
+                    ref other => serde_yaml::to_string(other)
+                        .map_err(|err| Error::Yaml(Box::new(err)))?
+                        .trim_end()
+                        .to_owned(),
+                };
+                path.push(k);
+                decrypt_tree(v, key, path, hasher)?;
+                path.pop();
+            }
+        }
+        // Items in a list share the path of the list itself.
+        Value::Sequence(ref mut seq) => {
+            for v in seq.iter_mut() {
+                decrypt_tree(v, key, path, hasher)?;
+            }
+        }
+        Value::String(ref mut s) if s.starts_with("ENC[") => {
+            let aad = format!("{}:", path.join(":"));
+            let plaintext = decrypt_value(s, key, &aad)?;
+            hasher.update(&plaintext);
+            *s = String::from_utf8(plaintext)?;
+        }
+        Value::String(ref s) => hasher.update(s.as_bytes()),
+        // SOPS is written in Python and Go, but it hashes booleans the way
+        // Python prints them.
+        Value::Bool(b) => hasher.update(if b { "True" } else { "False" }),
+        Value::Number(ref n) => hasher.update(n.to_string().as_bytes()),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Decrypt a single `ENC[AES256_GCM,...]` value.
+fn decrypt_value(encrypted: &str, key: &[u8], aad: &str) -> Result<Vec<u8>> {
+    // Only compile this Regex once.
+    lazy_static! {
+        static ref RE: Regex = Regex::new(
+            r"^ENC\[AES256_GCM,data:(?P<data>[^,]*),iv:(?P<iv>[^,]+),tag:(?P<tag>[^,]+),type:(?P<type>[^\]]+)\]$"
+        )
+        .unwrap();
+    }
+    let caps = RE.captures(encrypted).ok_or_else(|| Error::Parse {
+        input: encrypted.to_owned(),
+    })?;
+    let mut ciphertext = base64::decode(&caps["data"])?;
+    let iv = base64::decode(&caps["iv"])?;
+    ciphertext.extend(base64::decode(&caps["tag"])?);
+    if key.len() != 32 || iv.len() != 32 {
+        return Err(Error::Decrypt("invalid SOPS key or IV length".into()));
+    }
+
+    let cipher = SopsCipher::new_from_slice(key)
+        .map_err(|err| Error::Decrypt(err.to_string().into()))?;
+    let payload = Payload {
+        msg: &ciphertext,
+        aad: aad.as_bytes(),
+    };
+    cipher.decrypt(iv.as_slice().into(), payload).map_err(|_| {
+        Error::Decrypt(format!("could not decrypt SOPS value at {:?}", aad).into())
+    })
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "sops"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        let loc = secretfile.var(credential).cloned();
+        self.get_loc(credential, loc).await
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        let loc = secretfile.file(path).cloned();
+        self.get_loc(path, loc).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::age::armor::{ArmoredWriter, Format};
+    use ::age::secrecy::ExposeSecret;
+    use ::age::{x25519, Encryptor};
+    use aes_gcm::aead::{Aead, KeyInit, Payload};
+    use sha2::{Digest, Sha512};
+    use std::fs;
+    use std::io::Write;
+    use std::str::FromStr;
+
+    use super::{Client, SopsCipher};
+    use crate::backend::Backend;
+    use crate::secretfile::Secretfile;
+
+    const DATA_KEY: [u8; 32] = [7; 32];
+    const LAST_MODIFIED: &str = "2022-01-01T00:00:00Z";
+
+    /// Encrypt a value the way SOPS does.
+    fn encrypt(plaintext: &str, aad: &str, kind: &str) -> String {
+        let iv = [3; 32];
+        let cipher = SopsCipher::new_from_slice(&DATA_KEY).unwrap();
+        let payload = Payload {
+            msg: plaintext.as_bytes(),
+            aad: aad.as_bytes(),
+        };
+        let mut data = cipher.encrypt(iv.as_slice().into(), payload).unwrap();
+        let tag = data.split_off(data.len() - 16);
+        format!(
+            "ENC[AES256_GCM,data:{},iv:{},tag:{},type:{}]",
+            base64::encode(&data),
+            base64::encode(iv),
+            base64::encode(&tag),
+            kind
+        )
+    }
+
+    /// Write a SOPS file encrypted for a new identity, and return the
+    /// identity.  Files ending in `.json` are written as JSON, and others as
+    /// YAML.
+    fn write_sops_file(path: &std::path::Path, tamper: bool) -> x25519::Identity {
+        let identity = x25519::Identity::generate();
+        let encryptor =
+            Encryptor::with_recipients(vec![Box::new(identity.to_public())]).unwrap();
+        let mut armored = vec![];
+        let armor =
+            ArmoredWriter::wrap_output(&mut armored, Format::AsciiArmor).unwrap();
+        let mut writer = encryptor.wrap_output(armor).unwrap();
+        writer.write_all(&DATA_KEY).unwrap();
+        writer.finish().unwrap().finish().unwrap();
+        let enc = String::from_utf8(armored).unwrap();
+
+        // `json!` sorts keys, so this is the order in which values appear.
+        let mut hasher = Sha512::new();
+        for plaintext in &["s3cret", "5432", "admin", "True", "a", "b", "plain"] {
+            hasher.update(plaintext.as_bytes());
+        }
+        let mac = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>();
+
+        let password = if tamper { "t4mpered" } else { "s3cret" };
+        let doc = serde_json::json!({
+            "database": {
+                "username": encrypt("admin", "database:username:", "str"),
+                "password": encrypt(password, "database:password:", "str"),
+                "port": encrypt("5432", "database:port:", "int"),
+            },
+            "enabled_unencrypted": true,
+            "hosts": [
+                encrypt("a", "hosts:", "str"),
+                encrypt("b", "hosts:", "str"),
+            ],
+            "note_unencrypted": "plain",
+            "sops": {
+                "age": [{ "recipient": identity.to_public().to_string(), "enc": enc }],
+                "lastmodified": LAST_MODIFIED,
+                "mac": encrypt(&mac, LAST_MODIFIED, "str"),
+                "version": "3.7.1",
+            },
+        });
+        let text = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::to_string_pretty(&doc).unwrap()
+        } else {
+            serde_yaml::to_string(&doc).unwrap()
+        };
+        fs::write(path, text).unwrap();
+        identity
+    }
+
+    #[tokio::test]
+    async fn test_sops() {
+        for name in &["secrets.yaml", "secrets.json"] {
+            let dir = tempfile::tempdir().unwrap();
+            let secrets = dir.path().join(name);
+            let identity = write_sops_file(&secrets, false);
+            let identity_path = dir.path().join("keys.txt");
+            fs::write(&identity_path, identity.to_string().expose_secret()).unwrap();
+
+            let sf = Secretfile::from_str(&format!(
+                "\
+DB_USERNAME {path}:database.username
+DB_PASSWORD {path}:database.password
+DB_PORT {path}:database.port
+ENABLED {path}:enabled_unencrypted
+HOST {path}:hosts.1
+NOTE {path}:note_unencrypted
+MISSING {path}:database.missing
+NO_KEY {path}
+",
+                path = secrets.display()
+            ))
+            .unwrap();
+            let mut client = Client::with_identity_file(&identity_path).unwrap();
+            assert_eq!("admin", client.var(&sf, "DB_USERNAME").await.unwrap());
+            assert_eq!("s3cret", client.var(&sf, "DB_PASSWORD").await.unwrap());
+            assert_eq!("5432", client.var(&sf, "DB_PORT").await.unwrap());
+            assert_eq!("true", client.var(&sf, "ENABLED").await.unwrap());
+            assert_eq!("b", client.var(&sf, "HOST").await.unwrap());
+            assert_eq!("plain", client.var(&sf, "NOTE").await.unwrap());
+            assert!(client.var(&sf, "MISSING").await.is_err());
+            assert!(client.var(&sf, "NO_KEY").await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sops_bad_mac_or_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let secrets = dir.path().join("secrets.json");
+        let identity = write_sops_file(&secrets, true);
+        let identity_path = dir.path().join("keys.txt");
+        fs::write(&identity_path, identity.to_string().expose_secret()).unwrap();
+        let sf = Secretfile::from_str(&format!(
+            "DB_PASSWORD {}:database.password\n",
+            secrets.display()
+        ))
+        .unwrap();
+        let mut client = Client::with_identity_file(&identity_path).unwrap();
+        assert!(client.var(&sf, "DB_PASSWORD").await.is_err());
+
+        let other = x25519::Identity::generate();
+        fs::write(&identity_path, other.to_string().expose_secret()).unwrap();
+        let mut client = Client::with_identity_file(&identity_path).unwrap();
+        assert!(client.var(&sf, "DB_PASSWORD").await.is_err());
+    }
+}
@@ -0,0 +1,92 @@
+//! A backend which reads credentials passed in by systemd.
+//!
+//! systemd's `LoadCredential=` and `LoadCredentialEncrypted=` settings
+//! place each credential in a file in `$CREDENTIALS_DIRECTORY`.  We look up
+//! `credentials::var("DB_PASSWORD")` in the file `DB_PASSWORD`, unless the
+//! `Secretfile` maps it somewhere else:
+//!
+//! ```text
+//! DB_PASSWORD postgres-password
+//! ```
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::backend::Backend;
+use crate::directory;
+use crate::errors::*;
+use crate::secretfile::Secretfile;
+
+/// Fetches credentials from systemd's `$CREDENTIALS_DIRECTORY`.
+pub struct Client {
+    directory: directory::Client,
+}
+
+impl Client {
+    /// Are we running under systemd with credentials available?
+    pub fn is_enabled() -> bool {
+        env::var_os("CREDENTIALS_DIRECTORY").is_some()
+    }
+
+    /// Construct a new `systemd::Client` which reads from
+    /// `$CREDENTIALS_DIRECTORY`.
+    pub fn from_env() -> Result<Client> {
+        let dir = env::var_os("CREDENTIALS_DIRECTORY").ok_or_else(|| {
+            Error::UndefinedEnvironmentVariable {
+                name: "CREDENTIALS_DIRECTORY".to_owned(),
+                source: env::VarError::NotPresent,
+            }
+        })?;
+        Ok(Client::new(dir))
+    }
+
+    /// Construct a new `systemd::Client` which reads from `dir`.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Client {
+        Client {
+            directory: directory::Client::new(dir),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    fn name(&self) -> &'static str {
+        "systemd"
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn var(
+        &mut self,
+        secretfile: &Secretfile,
+        credential: &str,
+    ) -> Result<String> {
+        match secretfile.var(credential) {
+            Some(loc) => self.directory.get_loc(credential, Some(loc)),
+            None => self.directory.read(Path::new(credential)),
+        }
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, secretfile))]
+    async fn file(&mut self, secretfile: &Secretfile, path: &str) -> Result<String> {
+        self.directory.get_loc(path, secretfile.file(path))
+    }
+}
+
+#[tokio::test]
+async fn test_systemd() {
+    use std::fs;
+    use std::str::FromStr;
+
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("DB_PASSWORD"), "secret\n").unwrap();
+    fs::write(dir.path().join("api-token"), "abc").unwrap();
+
+    let sf =
+        Secretfile::from_str("API_TOKEN api-token\n>token.txt api-token\n").unwrap();
+    let mut client = Client::new(dir.path());
+    assert_eq!("secret", client.var(&sf, "DB_PASSWORD").await.unwrap());
+    assert_eq!("abc", client.var(&sf, "API_TOKEN").await.unwrap());
+    assert_eq!("abc", client.file(&sf, "token.txt").await.unwrap());
+    assert!(client.var(&sf, "NOSUCHVAR").await.is_err());
+    assert!(client.file(&sf, "DB_PASSWORD").await.is_err());
+}
@@ -0,0 +1,408 @@
+//! An in-process mock Vault server.
+//!
+//! This emulates the parts of the Vault API used by `credentials`, plus a
+//! few endpoints which are handy for testing services which talk to Vault
+//! directly:
+//!
+//! - `GET /v1/<path>` reads a secret,
+//! - `POST /v1/auth/<mount>/login` logs in using a Kubernetes JWT,
+//! - `GET /v1/auth/token/lookup-self` looks up the current token, and
+//! - `PUT /v1/sys/leases/renew` renews the lease of a dynamic secret.
+//!
+//! Requests can be made to fail using `fail_next`:
+//!
+//! ```
+//! # #[tokio::main]
+//! # async fn main() -> credentials::Result<()> {
+//! use credentials::testing::vault::{Failure, MockVault};
+//! use credentials::{Client, Options, Secretfile};
+//!
+//! let vault = MockVault::start().await?;
+//! vault.add_token("root");
+//! vault.set_secret("secret/db", vec![("password", "secret")]);
+//! vault.fail_next("secret/db", Failure::Status(503));
+//!
+//! let backend = credentials::vault::Client::new(vault.url(), "root");
+//! let options = Options::default()
+//!     .secretfile("DB_PASSWORD secret/db:password".parse::<Secretfile>()?)
+//!     .backends(vec![Box::new(backend)]);
+//! let mut client = Client::new(options).await?;
+//! assert!(client.var("DB_PASSWORD").await.is_err());
+//! assert_eq!("secret", client.var("DB_PASSWORD").await?);
+//! # Ok(())
+//! # }
+//! ```
+
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::errors::*;
+use crate::mock_http::{MockServer, Request};
+
+/// A scripted failure, to be returned by `MockVault` instead of a normal
+/// response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Failure {
+    /// Return the specified HTTP status, such as 403 or 503.
+    Status(u16),
+    /// Wait for the specified time, and then respond normally.
+    Delay(Duration),
+}
+
+/// A secret stored in our mock server.
+struct Secret {
+    data: BTreeMap<String, String>,
+    /// The lease duration of a dynamic secret, or `None` for a static
+    /// secret.
+    lease_duration: Option<u64>,
+}
+
+/// A Kubernetes role which can be used to log in.
+struct Role {
+    jwt: String,
+    token: String,
+}
+
+/// The state of our mock server.
+#[derive(Default)]
+struct State {
+    tokens: BTreeSet<String>,
+    secrets: BTreeMap<String, Secret>,
+    /// Kubernetes roles, indexed by `(mount, role)`.
+    roles: BTreeMap<(String, String), Role>,
+    /// Leases we've issued, and their durations.
+    leases: BTreeMap<String, u64>,
+    /// Scripted failures, indexed by path.
+    failures: BTreeMap<String, VecDeque<Failure>>,
+    /// The requests we've received, as `"METHOD path"`.
+    requests: Vec<String>,
+}
+
+/// The body of a Kubernetes login request.
+#[derive(Deserialize)]
+struct Login {
+    role: String,
+    jwt: String,
+}
+
+/// The body of a lease renewal request.
+#[derive(Deserialize)]
+struct Renew {
+    lease_id: String,
+    increment: Option<u64>,
+}
+
+/// A mock Vault server running on localhost.
+pub struct MockVault {
+    server: MockServer,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockVault {
+    /// Start a new mock Vault server in the background.  It will run until
+    /// the current `tokio` runtime exits.
+    pub async fn start() -> Result<MockVault> {
+        let state = Arc::new(Mutex::new(State::default()));
+        let server_state = state.clone();
+        let server = MockServer::start_with_delays(move |req| {
+            let mut state = lock(&server_state);
+            handle(&mut state, req)
+        })
+        .await?;
+        Ok(MockVault { server, state })
+    }
+
+    /// The address of this server, suitable for use as `VAULT_ADDR`.
+    pub fn url(&self) -> Url {
+        self.server.url()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+
+    /// Accept `token` as a valid Vault token.
+    pub fn add_token<S: Into<String>>(&self, token: S) {
+        self.state().tokens.insert(token.into());
+    }
+
+    /// Store a static secret at `path`, such as `secret/db`.
+    pub fn set_secret<I, K, V>(&self, path: &str, data: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.insert_secret(path, data, None);
+    }
+
+    /// Store a dynamic secret at `path`.  Each read will issue a new lease
+    /// which lasts for `lease_duration` seconds, and which can be renewed.
+    pub fn set_dynamic_secret<I, K, V>(&self, path: &str, data: I, lease_duration: u64)
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.insert_secret(path, data, Some(lease_duration));
+    }
+
+    fn insert_secret<I, K, V>(&self, path: &str, data: I, lease_duration: Option<u64>)
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let data = data
+            .into_iter()
+            .map(|(k, v)| (k.into(), v.into()))
+            .collect();
+        let secret = Secret {
+            data,
+            lease_duration,
+        };
+        self.state().secrets.insert(path.to_owned(), secret);
+    }
+
+    /// Allow Kubernetes logins to the auth method mounted at `mount` (usually
+    /// `kubernetes`) using `role` and `jwt`.  A successful login returns
+    /// `token`, which will then be accepted by the server.
+    pub fn add_kubernetes_role(
+        &self,
+        mount: &str,
+        role: &str,
+        jwt: &str,
+        token: &str,
+    ) {
+        let role_key = (mount.to_owned(), role.to_owned());
+        let role = Role {
+            jwt: jwt.to_owned(),
+            token: token.to_owned(),
+        };
+        self.state().roles.insert(role_key, role);
+    }
+
+    /// Make the next request for `path` fail.  `path` is relative to `/v1/`,
+    /// for example `secret/db` or `auth/kubernetes/login`, and it's matched
+    /// against request paths with their query strings removed, so
+    /// `secret/db` also matches `secret/db?version=2`.  Calling this several
+    /// times queues up several failures.
+    pub fn fail_next(&self, path: &str, failure: Failure) {
+        self.state()
+            .failures
+            .entry(path.to_owned())
+            .or_default()
+            .push_back(failure);
+    }
+
+    /// The requests we've received so far, in the form `"GET secret/db"`.
+    pub fn requests(&self) -> Vec<String> {
+        self.state().requests.clone()
+    }
+}
+
+/// Lock `state`, ignoring poisoning, because a panicking test can't leave
+/// our state inconsistent.
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Generate a Vault-style error response.
+fn error(status: u16, message: &str) -> (u16, Value) {
+    let errors: Vec<&str> = if message.is_empty() {
+        vec![]
+    } else {
+        vec![message]
+    };
+    (status, json!({ "errors": errors }))
+}
+
+/// Handle a single request.
+fn handle(state: &mut State, req: &Request) -> (u16, String, Duration) {
+    let path = req
+        .path
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches("/v1/")
+        .to_owned();
+    state.requests.push(format!("{} {}", req.method, path));
+
+    let mut delay = Duration::ZERO;
+    let failure = state.failures.get_mut(&path).and_then(|f| f.pop_front());
+    let (status, body) = match failure {
+        Some(Failure::Status(status)) => error(status, "scripted failure"),
+        Some(Failure::Delay(duration)) => {
+            delay = duration;
+            respond(state, req, &path)
+        }
+        None => respond(state, req, &path),
+    };
+    (status, body.to_string(), delay)
+}
+
+/// Generate a normal response to a request for `path`.
+fn respond(state: &mut State, req: &Request, path: &str) -> (u16, Value) {
+    if let Some(mount) = path
+        .strip_prefix("auth/")
+        .and_then(|p| p.strip_suffix("/login"))
+    {
+        return login(state, req, mount);
+    }
+
+    let authorized = req
+        .header("x-vault-token")
+        .is_some_and(|token| state.tokens.contains(token));
+    if !authorized {
+        return error(403, "permission denied");
+    }
+
+    match (req.method.as_str(), path) {
+        ("GET", "auth/token/lookup-self") => {
+            let token = req.header("x-vault-token").unwrap_or_default();
+            let data = json!({
+                "id": token,
+                "policies": ["default"],
+                "renewable": false,
+                "ttl": 0,
+            });
+            (200, json!({ "data": data }))
+        }
+        ("PUT", "sys/leases/renew") | ("POST", "sys/leases/renew") => {
+            let renew: Renew = match serde_json::from_slice(&req.body) {
+                Ok(renew) => renew,
+                Err(_) => return error(400, "invalid request"),
+            };
+            match state.leases.get(&renew.lease_id) {
+                Some(&duration) => {
+                    let duration = renew.increment.unwrap_or(duration);
+                    let body = json!({
+                        "lease_id": renew.lease_id,
+                        "renewable": true,
+                        "lease_duration": duration,
+                    });
+                    (200, body)
+                }
+                None => error(400, "lease not found or lease is not renewable"),
+            }
+        }
+        ("GET", _) => {
+            let secret = match state.secrets.get(path) {
+                Some(secret) => secret,
+                None => return error(404, ""),
+            };
+            let data = secret.data.clone();
+            let (lease_id, lease_duration) = match secret.lease_duration {
+                Some(duration) => {
+                    let lease_id = format!("{}/{}", path, state.leases.len() + 1);
+                    state.leases.insert(lease_id.clone(), duration);
+                    (lease_id, duration)
+                }
+                // Vault reports a default TTL of 32 days for KV secrets.
+                None => (String::new(), 2_764_800),
+            };
+            let body = json!({
+                "lease_id": lease_id,
+                "renewable": !lease_id.is_empty(),
+                "lease_duration": lease_duration,
+                "data": data,
+            });
+            (200, body)
+        }
+        _ => error(405, "unsupported operation"),
+    }
+}
+
+/// Handle a Kubernetes login request for the auth method at `mount`.
+fn login(state: &mut State, req: &Request, mount: &str) -> (u16, Value) {
+    let login: Login = match serde_json::from_slice(&req.body) {
+        Ok(login) => login,
+        Err(_) => return error(400, "missing role or jwt"),
+    };
+    let token = match state.roles.get(&(mount.to_owned(), login.role)) {
+        Some(role) if role.jwt == login.jwt => role.token.clone(),
+        _ => return error(403, "permission denied"),
+    };
+    state.tokens.insert(token.clone());
+    let auth = json!({
+        "client_token": token,
+        "policies": ["default"],
+        "lease_duration": 3600,
+        "renewable": true,
+    });
+    (200, json!({ "auth": auth }))
+}
+
+#[tokio::test]
+async fn test_mock_vault() {
+    let vault = MockVault::start().await.unwrap();
+    vault.add_token("root");
+    vault.set_dynamic_secret("database/creds/app", vec![("username", "u1")], 60);
+    let client = reqwest::Client::new();
+    let url = |path: &str| vault.url().join(path).unwrap();
+
+    // Look up our token.
+    let res = client
+        .get(url("v1/auth/token/lookup-self"))
+        .header("X-Vault-Token", "root")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(200, res.status().as_u16());
+    let body: Value = res.json().await.unwrap();
+    assert_eq!("root", body["data"]["id"]);
+
+    // Read a dynamic secret and renew its lease.
+    let res = client
+        .get(url("v1/database/creds/app"))
+        .header("X-Vault-Token", "root")
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert_eq!("u1", body["data"]["username"]);
+    let lease_id = body["lease_id"].as_str().unwrap().to_owned();
+    let res = client
+        .put(url("v1/sys/leases/renew"))
+        .header("X-Vault-Token", "root")
+        .json(&json!({ "lease_id": lease_id, "increment": 120 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(200, res.status().as_u16());
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(120, body["lease_duration"]);
+
+    // Unknown leases and tokens are rejected.
+    let res = client
+        .put(url("v1/sys/leases/renew"))
+        .header("X-Vault-Token", "root")
+        .json(&json!({ "lease_id": "nope" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(400, res.status().as_u16());
+    let res = client
+        .get(url("v1/auth/token/lookup-self"))
+        .header("X-Vault-Token", "bad")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(403, res.status().as_u16());
+
+    assert_eq!(
+        vec![
+            "GET auth/token/lookup-self",
+            "GET database/creds/app",
+            "PUT sys/leases/renew",
+            "PUT sys/leases/renew",
+            "GET auth/token/lookup-self",
+        ],
+        vault.requests()
+    );
+}
@@ -4,6 +4,7 @@ use std::fs;
 use std::path::Path;
 
 use crate::errors::*;
+use crate::http::send_json;
 
 /// Path to a Kubernetes service account API token (automatically mounted into
 /// the container if one is available and `automountServiceAccountToken` is not
@@ -51,43 +52,16 @@ async fn auth(
 ) -> Result<String> {
     let url = addr.join(&format!("v1/auth/{}/login", auth_path))?;
     let payload = VaultKubernetesLogin { role, jwt };
-    let mkerr = |err| Error::Url {
-        url: url.to_owned(),
-        source: Box::new(err),
-    };
-    let res = client
+    let req = client
         .post(url.clone())
         // Leaving the connection open will cause errors on reconnect
         // after inactivity.
         //
         // TODO: Is this still true?
         .header("Connection", "close")
-        .body(serde_json::to_vec(&payload)?)
-        .send()
-        .await
-        .map_err(|err| (&mkerr)(Error::Other(err.into())))?;
-
-    if res.status().is_success() {
-        // Parse our body and get the auth token.
-        // Read our HTTP body.
-        let auth_res = res
-            .json::<VaultAuthResponse>()
-            .await
-            .map_err(|err| (&mkerr)(Error::Other(err.into())))?;
-        Ok(auth_res.auth.client_token)
-    } else {
-        // Generate informative errors for HTTP failures.
-        let status = res.status().to_owned();
-        let body = res
-            .text()
-            .await
-            .map_err(|err| (&mkerr)(Error::Other(err.into())))?;
-
-        Err(mkerr(Error::UnexpectedHttpStatus {
-            status,
-            body: body.trim().to_owned(),
-        }))
-    }
+        .body(serde_json::to_vec(&payload)?);
+    let auth_res: VaultAuthResponse = send_json(&url, req).await?;
+    Ok(auth_res.auth.client_token)
 }
 
 /// If `VAULT_KUBERNETES_ROLE` is set, attempt to get a Vault token by
@@ -105,3 +79,26 @@ pub(crate) async fn vault_kubernetes_token(
     let client = reqwest::Client::new();
     Ok(Some(auth(client, addr, &auth_path, &role, &jwt).await?))
 }
+
+#[cfg(feature = "testing")]
+#[tokio::test]
+async fn test_auth() {
+    use crate::testing::vault::{Failure, MockVault};
+
+    let vault = MockVault::start().await.unwrap();
+    vault.add_kubernetes_role("k8s", "app", "jwt123", "s.token");
+    let client = reqwest::Client::new();
+    let url = vault.url();
+
+    let token = auth(client.clone(), &url, "k8s", "app", "jwt123").await;
+    assert_eq!("s.token", token.unwrap());
+    assert!(auth(client.clone(), &url, "k8s", "app", "wrong")
+        .await
+        .is_err());
+    assert!(auth(client.clone(), &url, "other", "app", "jwt123")
+        .await
+        .is_err());
+
+    vault.fail_next("auth/k8s/login", Failure::Status(503));
+    assert!(auth(client, &url, "k8s", "app", "jwt123").await.is_err());
+}
@@ -10,7 +10,8 @@ use tracing::debug;
 
 use crate::backend::Backend;
 use crate::errors::*;
-use crate::secretfile::{Location, Secretfile, SecretfileLookup};
+use crate::http::send_json;
+use crate::secretfile::{Location, Secretfile};
 
 mod kubernetes;
 
@@ -86,21 +87,26 @@ impl Client {
         let client = reqwest::Client::new();
         let addr = default_addr()?.parse()?;
         let token = default_token(&addr).await?;
-        Client::new(client, addr, token)
+        Ok(Client::with_http_client(client, addr, token))
     }
 
-    /// Create a new Vault client.
-    fn new<U, S>(client: reqwest::Client, addr: U, token: S) -> Result<Client>
+    /// Construct a new `vault::Client` which talks to the server at `addr`,
+    /// using `token`.
+    pub fn new<S: Into<String>>(addr: Url, token: S) -> Client {
+        Client::with_http_client(reqwest::Client::new(), addr, token)
+    }
+
+    /// Create a new Vault client using the specified HTTP client.
+    fn with_http_client<S>(client: reqwest::Client, addr: Url, token: S) -> Client
     where
-        U: Into<Url>,
         S: Into<String>,
     {
-        Ok(Client {
+        Client {
             client,
-            addr: addr.into(),
+            addr,
             token: token.into(),
             secrets: BTreeMap::new(),
-        })
+        }
     }
 
     /// Fetch a secret from the Vault server.
@@ -108,41 +114,14 @@ impl Client {
         let url = self.addr.join(&format!("v1/{}", path))?;
         debug!("Getting secret {}", url);
 
-        let mkerr = |err| Error::Url {
-            url: url.clone(),
-            source: Box::new(err),
-        };
-        let res = self
+        let req = self
             .client
             .get(url.clone())
             // Leaving the connection open will cause errors on reconnect
             // after inactivity.
             .header("Connection", "close")
-            .header("X-Vault-Token", &self.token[..])
-            .send()
-            .await
-            .map_err(|err| (&mkerr)(Error::Other(err.into())))?;
-
-        if res.status().is_success() {
-            Ok(res
-                .json()
-                .await
-                .map_err(|err| (&mkerr)(Error::Other(err.into())))?)
-        } else {
-            // Generate informative errors for HTTP failures, because these can
-            // be caused by everything from bad URLs to overly restrictive vault
-            // policies.
-            let status = res.status().to_owned();
-            let body = res
-                .text()
-                .await
-                .map_err(|err| (&mkerr)(Error::Other(err.into())))?;
-
-            Err(mkerr(Error::UnexpectedHttpStatus {
-                status,
-                body: body.trim().to_owned(),
-            }))
-        }
+            .header("X-Vault-Token", &self.token[..]);
+        send_json(&url, req).await
     }
 
     async fn get_loc(
@@ -177,7 +156,7 @@ impl Client {
                         secret: path.to_owned(),
                         key: key.to_owned(),
                     })
-                    .map(|v| v.clone())
+                    .cloned()
             }
             Some(Location::Path(ref path)) => Err(Error::MissingKeyInPath {
                 path: path.to_owned(),
@@ -209,40 +188,88 @@ impl Backend for Client {
     }
 }
 
-// Tests disabled until we can mock reqwest.
-//
-//#[cfg(test)]
-//mod tests {
-//    use backend::Backend;
-//    use hyper;
-//    use secretfile::Secretfile;
-//    use super::Client;
-//
-//    mock_connector!(MockVault {
-//        "http://127.0.0.1" =>
-//          "HTTP/1.1 200 OK\r\n\
-//           Content-Type: application/json\r\n\
-//           \r\n\
-//           {\"data\": {\"value\": \"bar\"},\"lease_duration\": 2592000}\r\n\
-//           "
-//    });
-//
-//    fn test_client() -> Client {
-//        let h = reqwest::Client::with_connector(MockVault::default());
-//        Client::new(h, "http://127.0.0.1", "123").unwrap()
-//    }
-//
-//    #[test]
-//    fn test_get_secret() {
-//        let client = test_client();
-//        let secret = client.get_secret("secret/foo").unwrap();
-//        assert_eq!("bar", secret.data.get("value").unwrap());
-//    }
-//
-//    #[test]
-//    fn test_var() {
-//        let sf = Secretfile::from_str("FOO secret/foo:value").unwrap();
-//        let mut client = test_client();
-//        assert_eq!("bar", client.var(&sf, "FOO").unwrap());
-//    }
-//}
+// These tests use the mock Vault server from our `testing` feature.
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    use super::Client;
+    use crate::backend::Backend;
+    use crate::errors::Error;
+    use crate::secretfile::Secretfile;
+    use crate::testing::vault::{Failure, MockVault};
+
+    async fn test_vault() -> (MockVault, Client) {
+        let vault = MockVault::start().await.unwrap();
+        vault.add_token("123");
+        vault.set_secret("secret/foo", vec![("value", "bar")]);
+        let client = Client::new(vault.url(), "123");
+        (vault, client)
+    }
+
+    #[tokio::test]
+    async fn test_get_secret() {
+        let (_vault, client) = test_vault().await;
+        let secret = client.get_secret("secret/foo").await.unwrap();
+        assert_eq!("bar", secret.data.get("value").unwrap());
+        assert_eq!(2_764_800, secret.lease_duration);
+    }
+
+    #[tokio::test]
+    async fn test_var() {
+        let (vault, mut client) = test_vault().await;
+        let sf = Secretfile::from_str(
+            "FOO secret/foo:value\nMISSING secret/foo:missing\nNO_KEY secret/foo\n",
+        )
+        .unwrap();
+        assert_eq!("bar", client.var(&sf, "FOO").await.unwrap());
+        assert!(client.var(&sf, "MISSING").await.is_err());
+        assert!(client.var(&sf, "NO_KEY").await.is_err());
+
+        // We only fetch each secret once.
+        assert_eq!(vec!["GET secret/foo"], vault.requests());
+    }
+
+    #[tokio::test]
+    async fn test_http_errors() {
+        let (vault, client) = test_vault().await;
+        let bad_token = Client::new(vault.url(), "bad");
+        let status = |err: Error| match err {
+            Error::Url { source, .. } => match *source {
+                Error::UnexpectedHttpStatus { status, .. } => status.as_u16(),
+                other => panic!("unexpected error: {:?}", other),
+            },
+            other => panic!("unexpected error: {:?}", other),
+        };
+
+        let err = bad_token.get_secret("secret/foo").await.unwrap_err();
+        assert_eq!(403, status(err));
+        let err = client.get_secret("secret/missing").await.unwrap_err();
+        assert_eq!(404, status(err));
+
+        // Scripted failures only affect the next request.
+        vault.fail_next("secret/foo", Failure::Status(503));
+        let err = client.get_secret("secret/foo").await.unwrap_err();
+        assert_eq!(503, status(err));
+        assert!(client.get_secret("secret/foo").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_slow_response() {
+        let (vault, client) = test_vault().await;
+        vault.fail_next("secret/foo", Failure::Delay(Duration::from_millis(200)));
+        let start = Instant::now();
+        assert!(client.get_secret("secret/foo").await.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        // A client with a shorter timeout gives up.
+        vault.fail_next("secret/foo", Failure::Delay(Duration::from_millis(200)));
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let impatient = Client::with_http_client(http, vault.url(), "123");
+        assert!(impatient.get_secret("secret/foo").await.is_err());
+    }
+}